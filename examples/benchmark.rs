@@ -30,20 +30,29 @@ struct Generator {
     stats: Sink<Metric>,
     t0: Option<Instant>,
     gauge: u64,
+    guards: bool,
 }
 
 impl Generator {
-    fn new(stats: Sink<Metric>) -> Generator {
+    fn new(stats: Sink<Metric>, guards: bool) -> Generator {
         Generator {
-            stats: stats,
+            stats,
             t0: None,
             gauge: 0,
+            guards,
         }
     }
 
     fn run(&mut self) {
         loop {
             self.gauge += 1;
+            if self.guards {
+                // Every guard sends its timing as a batch of its own.
+                let _guard = self.stats.time(Metric::Ok);
+                let _ = self.stats.send(Sample::Value(Metric::Total, self.gauge));
+                continue;
+            }
+
             let t1 = Instant::now();
             if let Some(t0) = self.t0 {
                 let _ = self.stats.send(Sample::Timing(Metric::Ok, t0, t1, 1));
@@ -66,6 +75,7 @@ pub fn opts() -> Options {
     opts.optopt("p", "producers", "number of producers", "INTEGER");
     opts.optopt("c", "capacity", "maximum number of unprocessed batches", "INTEGER");
     opts.optopt("b", "batch", "number of samples per source batch", "INTEGER");
+    opts.optflag("g", "guards", "time operations with timing guards, rather than sending timing samples");
     opts.optflag("h", "help", "print this help menu");
 
     opts
@@ -106,10 +116,12 @@ fn main() {
     let producers = matches.opt_str("producers").unwrap_or_else(|| "1".to_owned())
         .parse()
         .unwrap();
+    let guards = matches.opt_present("guards");
 
     info!("producers: {}", producers);
     info!("capacity: {}", capacity);
     info!("batch size: {}", batch);
+    info!("timing guards: {}", guards);
 
     let mut receiver = Receiver::builder()
         .capacity(capacity)
@@ -126,7 +138,7 @@ fn main() {
     // Spin up our sample producers.
     for _ in 0..producers {
         let s = receiver.get_sink();
-        thread::spawn(move || { Generator::new(s).run(); });
+        thread::spawn(move || { Generator::new(s, guards).run(); });
     }
 
     // Spin up the sink and let 'er rip.
@@ -3,15 +3,24 @@ use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use crossbeam_channel;
 use std::any::Any;
 use std::error;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, mpsc};
 use std::{fmt, io};
 
 pub fn channel<T>(bound: usize) -> (Sender<T>, Receiver<T>) {
+    from_parts(crossbeam_channel::bounded(bound))
+}
+
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    from_parts(crossbeam_channel::unbounded())
+}
+
+fn from_parts<T>(
+    (tx, rx): (crossbeam_channel::Sender<T>, crossbeam_channel::Receiver<T>),
+) -> (Sender<T>, Receiver<T>) {
     let (tx_ctl, rx_ctl) = control();
-    let (tx, rx) = crossbeam_channel::bounded(bound);
 
-    let tx = Sender { tx, ctl: tx_ctl };
+    let tx = Sender { tx, stealer: rx.clone(), ctl: tx_ctl };
     let rx = Receiver { rx, ctl: rx_ctl };
 
     (tx, rx)
@@ -21,6 +30,7 @@ fn control() -> (SenderControl, ReceiverControl) {
     let inner = Arc::new(Inner {
         pending: AtomicUsize::new(0),
         senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
         set_readiness: AtomicLazyCell::new(),
     });
 
@@ -45,6 +55,7 @@ struct ReceiverControl {
 
 pub struct Sender<T> {
     tx: crossbeam_channel::Sender<T>,
+    stealer: crossbeam_channel::Receiver<T>,
     ctl: SenderControl,
 }
 
@@ -67,11 +78,16 @@ pub enum RecvError {
 struct Inner {
     pending: AtomicUsize,
     senders: AtomicUsize,
+    closed: AtomicBool,
     set_readiness: AtomicLazyCell<SetReadiness>,
 }
 
 impl<T> Sender<T> {
     pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if self.ctl.inner.closed.load(Ordering::Acquire) {
+            return Err(SendError::Disconnected(t));
+        }
+
         match self.tx.is_full() {
             true => Err(SendError::Full(t)),
             false => {
@@ -81,18 +97,36 @@ impl<T> Sender<T> {
             }
         }
     }
+
+    /// Whether or not the receiving side has closed the channel, or been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.ctl.inner.closed.load(Ordering::Acquire)
+    }
+
+    /// Takes the oldest message out of the channel, if there is one, before the receiver can.
+    pub fn steal(&self) -> Option<T> {
+        self.stealer.try_recv().inspect(|_| {
+            let _ = self.ctl.inner.mark_receive();
+        })
+    }
 }
 
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Sender<T> {
         Sender {
             tx: self.tx.clone(),
+            stealer: self.stealer.clone(),
             ctl: self.ctl.clone(),
         }
     }
 }
 
 impl<T> Receiver<T> {
+    /// Closes the channel, so that every later send fails as if the receiver had been dropped.
+    pub fn close(&self) {
+        self.ctl.inner.closed.store(true, Ordering::Release);
+    }
+
     pub fn recv(&self) -> Result<T, RecvError> {
         if self.ctl.inner.senders.load(Ordering::Relaxed) == 0 {
             return Err(RecvError::Disconnected);
@@ -101,7 +135,7 @@ impl<T> Receiver<T> {
         match self.rx.try_recv() {
             None => Err(RecvError::Empty),
             Some(msg) => {
-                let _ = self.ctl.mark_receive();
+                let _ = self.ctl.inner.mark_receive();
                 Ok(msg)
             }
         }
@@ -139,7 +173,7 @@ impl SenderControl {
         let cnt = self.inner.pending.fetch_add(1, Ordering::Acquire);
         if cnt == 0 {
             if let Some(set_readiness) = self.inner.set_readiness.borrow() {
-                try!(set_readiness.set_readiness(Ready::readable()));
+                set_readiness.set_readiness(Ready::readable())?;
             }
         }
 
@@ -163,22 +197,22 @@ impl Drop for SenderControl {
     }
 }
 
-impl ReceiverControl {
+impl Inner {
     fn mark_receive(&self) -> io::Result<()> {
-        let first = self.inner.pending.load(Ordering::Acquire);
+        let first = self.pending.load(Ordering::Acquire);
         if first == 1 {
             // We're empty now after receiving the message, so mark it.
-            if let Some(set_readiness) = self.inner.set_readiness.borrow() {
-                try!(set_readiness.set_readiness(Ready::empty()));
+            if let Some(set_readiness) = self.set_readiness.borrow() {
+                set_readiness.set_readiness(Ready::empty())?;
             }
         }
 
-        let second = self.inner.pending.fetch_sub(1, Ordering::AcqRel);
+        let second = self.pending.fetch_sub(1, Ordering::AcqRel);
         if first == 1 && second > 1 {
             // More messages came in between our last receive and now, so make sure we mark the
             // channel as readable again.
-            if let Some(set_readiness) = self.inner.set_readiness.borrow() {
-                try!(set_readiness.set_readiness(Ready::readable()));
+            if let Some(set_readiness) = self.set_readiness.borrow() {
+                set_readiness.set_readiness(Ready::readable())?;
             }
         }
 
@@ -186,6 +220,12 @@ impl ReceiverControl {
     }
 }
 
+impl Drop for ReceiverControl {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::Release);
+    }
+}
+
 impl Evented for ReceiverControl {
     fn register(
         &self,
@@ -195,8 +235,7 @@ impl Evented for ReceiverControl {
         opts: PollOpt,
     ) -> io::Result<()> {
         if self.registration.borrow().is_some() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+            return Err(io::Error::other(
                 "receiver already registered",
             ));
         }
@@ -227,8 +266,7 @@ impl Evented for ReceiverControl {
     ) -> io::Result<()> {
         match self.registration.borrow() {
             Some(registration) => poll.reregister(registration, token, interest, opts),
-            None => Err(io::Error::new(
-                io::ErrorKind::Other,
+            None => Err(io::Error::other(
                 "receiver not registered",
             )),
         }
@@ -237,8 +275,7 @@ impl Evented for ReceiverControl {
     fn deregister(&self, poll: &Poll) -> io::Result<()> {
         match self.registration.borrow() {
             Some(registration) => poll.deregister(registration),
-            None => Err(io::Error::new(
-                io::ErrorKind::Other,
+            None => Err(io::Error::other(
                 "receiver not registered",
             )),
         }
@@ -261,11 +298,10 @@ impl<T> From<mpsc::TrySendError<T>> for SendError<T> {
 }
 
 impl<T: Any> error::Error for SendError<T> {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            SendError::Io(ref io_err) => io_err.description(),
-            SendError::Full(..) => "full",
-            SendError::Disconnected(..) => "disconnected",
+            SendError::Io(ref io_err) => Some(io_err),
+            _ => None,
         }
     }
 }
@@ -291,14 +327,7 @@ fn format_send_error<T>(e: &SendError<T>, f: &mut fmt::Formatter) -> fmt::Result
     }
 }
 
-impl error::Error for RecvError {
-    fn description(&self) -> &str {
-        match *self {
-            RecvError::Empty => "empty",
-            RecvError::Disconnected => "disconnected",
-        }
-    }
-}
+impl error::Error for RecvError {}
 
 impl fmt::Debug for RecvError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -323,7 +352,7 @@ fn format_recv_error(e: &RecvError, f: &mut fmt::Formatter) -> fmt::Result {
 
 #[cfg(test)]
 mod tests {
-    use super::{channel, RecvError};
+    use super::{channel, RecvError, SendError};
     use mio::{Poll, Events, Token, PollOpt, Ready};
     use std::thread;
     use std::time::Duration;
@@ -534,6 +563,46 @@ mod tests {
         assert_eq!(0, num);
     }
 
+    #[test]
+    pub fn test_steal_oldest_message() {
+        let poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1024);
+        let (tx, rx) = channel(16);
+
+        poll.register(&rx, Token(123), Ready::readable(), PollOpt::level()).unwrap();
+
+        tx.send("first").unwrap();
+        tx.send("second").unwrap();
+
+        // Stealing takes the oldest message, and the channel is still readable.
+        assert_eq!(Some("first"), tx.steal());
+        let num = poll.poll(&mut events, Some(Duration::from_millis(300))).unwrap();
+        assert_eq!(1, num);
+
+        // Once everything is stolen, there's nothing left to read.
+        assert_eq!(Some("second"), tx.steal());
+        assert_eq!(None, tx.steal());
+        let num = poll.poll(&mut events, Some(Duration::from_millis(300))).unwrap();
+        assert_eq!(0, num);
+
+        match rx.recv() {
+            Err(RecvError::Empty) => {}
+            no => panic!("unexpected value {:?}", no),
+        }
+    }
+
+    #[test]
+    pub fn test_send_after_receiver_dropped() {
+        let (tx, rx) = channel(16);
+        tx.send("hello").unwrap();
+
+        drop(rx);
+        match tx.send("goodbye") {
+            Err(SendError::Disconnected("goodbye")) => {}
+            no => panic!("unexpected value {:?}", no),
+        }
+    }
+
     #[test]
     pub fn test_sending_from_other_thread_while_polling() {
         const ITERATIONS: usize = 20;
@@ -564,7 +633,7 @@ mod tests {
                     assert_eq!(1, num);
                     assert_eq!(events.iter().next().unwrap().token(), Token(0));
 
-                    while let Ok(_) = rx.recv() {
+                    while rx.recv().is_ok() {
                         recv += 1;
                     }
                 }
@@ -0,0 +1,75 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+///
+/// The `Receiver` uses its clock for histogram windowing, scheduling observers, and timing delta
+/// snapshots, and every `Sink` uses it for timing guards.  By default, that's `SystemClock`: a
+/// `MockClock` can be configured via `Configuration::clock` to control time in tests.
+pub trait Clock: Send + Sync {
+    /// Gets the current time.
+    fn now(&self) -> Instant;
+}
+
+/// A clock that reads the system's monotonic clock, via `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// `MockClock` is cloneable, and all clones share the same time: hand one clone to
+/// `Configuration::clock`, and keep another to advance time with.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    /// Creates a new `MockClock`, starting at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward by the given amount.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, MockClock};
+    use std::time::Duration;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        shared.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+    }
+}
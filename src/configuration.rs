@@ -2,7 +2,10 @@ use receiver::Receiver;
 use std::hash::Hash;
 use std::fmt::Display;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::time::Duration;
+use clock::{Clock, SystemClock};
+use data::{FacetKind, Percentile, Sample, default_percentiles};
 
 /// A configuration builder for `Receiver`.
 #[derive(Clone)]
@@ -11,6 +14,18 @@ pub struct Configuration<T> {
     pub(crate) capacity: usize,
     pub(crate) batch_size: usize,
     pub(crate) poll_delay: Option<Duration>,
+    pub(crate) snapshot_timeout: Option<Duration>,
+    pub(crate) backpressure: BackpressurePolicy,
+    pub(crate) histogram: HistogramConfiguration,
+    pub(crate) percentiles: Vec<Percentile>,
+    pub(crate) auto_registration: Option<AutoRegistration>,
+    pub(crate) counter_limit: Option<usize>,
+    pub(crate) gauge_limit: Option<usize>,
+    pub(crate) histogram_limit: Option<usize>,
+    pub(crate) series_limit: Option<usize>,
+    pub(crate) cardinality_policy: CardinalityPolicy<T>,
+    pub(crate) instrumentation: bool,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl<T> Default for Configuration<T> {
@@ -20,6 +35,18 @@ impl<T> Default for Configuration<T> {
             capacity: 128,
             batch_size: 128,
             poll_delay: Some(Duration::from_millis(100)),
+            snapshot_timeout: Some(Duration::from_secs(10)),
+            backpressure: BackpressurePolicy::Block,
+            histogram: HistogramConfiguration::default(),
+            percentiles: default_percentiles(),
+            auto_registration: None,
+            counter_limit: None,
+            gauge_limit: None,
+            histogram_limit: None,
+            series_limit: None,
+            cardinality_policy: CardinalityPolicy::Reject,
+            instrumentation: false,
+            clock: Arc::new(SystemClock),
         }
     }
 }
@@ -35,8 +62,9 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
     /// Defaults to `128`.
     ///
     /// This controls how many buffers are pre-allocated, and conversely, how many buffers are able
-    /// to be sent into the data channel without blocking.  If all buffers are consumed, any send
-    /// activity by a `Sink` will block until a buffer is returned and made available.
+    /// to be sent into the data channel without blocking.  If all buffers are consumed, what a
+    /// `Sink` does is controlled by the backpressure policy: by default, any send activity will
+    /// block until a buffer is returned and made available.
     ///
     /// Tweaking this value allows for controlling the trade-off between memory consumption -- as
     /// all buffers are preallocated -- and throughput burst capabilities as a burst of metrics
@@ -83,8 +111,493 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
         self
     }
 
+    /// Sets the snapshot timeout.
+    ///
+    /// Defaults to `10s`.
+    ///
+    /// This controls how long a `Controller` waits for the receiver to respond to a request, such
+    /// as for a snapshot, before giving up with `Error::SnapshotTimeout`.  If set to `None`, a
+    /// `Controller` will wait indefinitely.
+    pub fn snapshot_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.snapshot_timeout = timeout;
+        self
+    }
+
+    /// Sets the backpressure policy.
+    ///
+    /// Defaults to `BackpressurePolicy::Block`.
+    ///
+    /// This controls what a `Sink` does when it needs a buffer but all buffers are in use, which
+    /// happens when samples are sent faster than the receiver can process them.  See
+    /// `BackpressurePolicy` for the available policies.
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.backpressure = policy;
+        self
+    }
+
+    /// Sets the default histogram window.
+    ///
+    /// Defaults to `10s`.
+    ///
+    /// Histograms, which back the timing and value percentile facets, only track values seen
+    /// within the window, so percentiles reflect recent activity rather than all-time activity.
+    ///
+    /// Can be overridden for specific metrics via `Receiver::add_facet_with_histogram`.
+    pub fn histogram_window(mut self, window: Duration) -> Self {
+        self.histogram = self.histogram.window(window);
+        self
+    }
+
+    /// Sets the default histogram granularity.
+    ///
+    /// Defaults to `1s`.
+    ///
+    /// This controls how often the oldest values are expired from a histogram, and so how
+    /// precisely the window is tracked.  Every histogram holds `window / granularity + 1`
+    /// sub-histograms, so a finer granularity will use more memory.
+    ///
+    /// Can be overridden for specific metrics via `Receiver::add_facet_with_histogram`.
+    pub fn histogram_granularity(mut self, granularity: Duration) -> Self {
+        self.histogram = self.histogram.granularity(granularity);
+        self
+    }
+
+    /// Sets the default number of significant figures tracked by histograms.
+    ///
+    /// Defaults to `3`.
+    ///
+    /// Values are tracked with a relative error of no more than one part in `10^figures`, and so
+    /// more significant figures provide more accurate percentiles at the cost of memory.
+    ///
+    /// Can be overridden for specific metrics via `Receiver::add_facet_with_histogram`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `figures` is greater than 5.
+    pub fn histogram_significant_figures(mut self, figures: u8) -> Self {
+        self.histogram = self.histogram.significant_figures(figures);
+        self
+    }
+
+    /// Sets the default bounds of values tracked by histograms.
+    ///
+    /// Defaults to a lowest discernible value of `1`, and a highest trackable value of
+    /// `u64::MAX`.
+    ///
+    /// Values outside of the bounds are clamped, and so are not tracked accurately.  Tighter bounds
+    /// use less memory.
+    ///
+    /// Can be overridden for specific metrics via `Receiver::add_facet_with_histogram`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `lowest` is zero, or if `highest` is less than twice `lowest`.
+    pub fn histogram_bounds(mut self, lowest: u64, highest: u64) -> Self {
+        self.histogram = self.histogram.bounds(lowest, highest);
+        self
+    }
+
+    /// Sets whether or not summaries include their histograms, encoded, by default.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// See `HistogramConfiguration::encoded` for details.  Can be overridden for specific metrics
+    /// via `Receiver::add_facet_with_histogram`.
+    pub fn histogram_encoded(mut self, encoded: bool) -> Self {
+        self.histogram = self.histogram.encoded(encoded);
+        self
+    }
+
+    /// Sets the default percentiles.
+    ///
+    /// Defaults to min, p50, p90, p99, p999 and max.
+    ///
+    /// These are the percentiles extracted from every timing and value percentile facet when
+    /// taking a snapshot.  Fewer percentiles make for smaller snapshots.
+    ///
+    /// Can be overridden for specific metrics via `HistogramConfiguration::percentiles`.
+    pub fn percentiles(mut self, percentiles: Vec<Percentile>) -> Self {
+        self.percentiles = percentiles;
+        self
+    }
+
+    /// Enables auto-registration of facets.
+    ///
+    /// Disabled by default.
+    ///
+    /// Samples for metric keys without any registered facets are normally ignored.  With
+    /// auto-registration enabled, the first sample seen for such a key registers a default set of
+    /// facets for it, based on the kind of sample, and is then processed as usual.  See
+    /// `AutoRegistration` for the defaults, and for limiting how many keys can be registered this
+    /// way.
+    pub fn auto_registration(mut self, auto_registration: AutoRegistration) -> Self {
+        self.auto_registration = Some(auto_registration);
+        self
+    }
+
+    /// Sets the maximum number of metric keys with a count facet.
+    ///
+    /// Defaults to no limit.
+    ///
+    /// Only keys are counted, not their labeled series: see `series_limit` for limiting those.
+    /// What happens when registering a key beyond the limit is controlled by the cardinality
+    /// policy.
+    pub fn counter_limit(mut self, limit: usize) -> Self {
+        self.counter_limit = Some(limit);
+        self
+    }
+
+    /// Sets the maximum number of metric keys with a gauge facet.
+    ///
+    /// Defaults to no limit.
+    ///
+    /// Only keys are counted, not their labeled series: see `series_limit` for limiting those.
+    /// What happens when registering a key beyond the limit is controlled by the cardinality
+    /// policy.
+    pub fn gauge_limit(mut self, limit: usize) -> Self {
+        self.gauge_limit = Some(limit);
+        self
+    }
+
+    /// Sets the maximum number of metric keys with a timing or value percentile facet.
+    ///
+    /// Defaults to no limit.
+    ///
+    /// Only keys are counted, not their labeled series: see `series_limit` for limiting those.  A
+    /// key with both timing and value percentile facets is only counted once.  What happens when
+    /// registering a key beyond the limit is controlled by the cardinality policy.
+    pub fn histogram_limit(mut self, limit: usize) -> Self {
+        self.histogram_limit = Some(limit);
+        self
+    }
+
+    /// Sets the maximum number of labeled series for any one metric key.
+    ///
+    /// Defaults to no limit.
+    ///
+    /// Each distinct set of labels sent for a key is held as its own series, so a label with
+    /// unbounded values, such as a user ID, grows a key without bound.  Once a key holds this many
+    /// labeled series, samples with any other set of labels are ignored for that key, and counted
+    /// in the `hotmic_rejected_series` internal metric, if instrumentation is enabled.  The series
+    /// without labels is always kept, and doesn't count towards the limit.
+    pub fn series_limit(mut self, limit: usize) -> Self {
+        self.series_limit = Some(limit);
+        self
+    }
+
+    /// Sets the cardinality policy.
+    ///
+    /// Defaults to `CardinalityPolicy::Reject`.
+    ///
+    /// This controls what happens when registering a metric key would exceed the counter, gauge or
+    /// histogram limit.  See `CardinalityPolicy` for the available policies.
+    pub fn cardinality_policy(mut self, policy: CardinalityPolicy<T>) -> Self {
+        self.cardinality_policy = policy;
+        self
+    }
+
+    /// Sets whether or not self-instrumentation metrics are included in snapshots.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// When enabled, every `Snapshot` includes internal metrics describing the receiver itself:
+    ///
+    /// - `hotmic_dropped_samples`, counting samples dropped due to backpressure
+    /// - `hotmic_rejected_registrations`, `hotmic_evicted_keys` and `hotmic_rejected_series`,
+    ///   counting what the cardinality limits turned away
+    /// - `hotmic_invalid_samples`, counting samples that couldn't be recorded
+    /// - `hotmic_clamped_samples`, counting gauge values too large to be held exactly
+    /// - `hotmic_encoding_failures`, counting histograms that couldn't be encoded, with
+    ///   `HistogramConfiguration::encoded` enabled
+    /// - `hotmic_samples_processed` and `hotmic_batches_processed`, counting what the receiver has
+    ///   processed
+    /// - `hotmic_dropped_batches`, counting whole batches dropped due to backpressure
+    /// - `hotmic_full_sends`, counting sends of a batch that found the data channel full
+    /// - `hotmic_snapshot_duration_ns`, a gauge of how long the previous snapshot took to build,
+    ///   as the one holding it is still being built
+    /// - `hotmic_buffer_pool_available`, a gauge of how many buffers are waiting in the pool
+    /// - `hotmic_registered_counters`, `hotmic_registered_gauges` and
+    ///   `hotmic_registered_histograms`, gauges of how many metric keys are registered
+    ///
+    /// When disabled, snapshots hold no internal metrics, so exporters render none.
+    pub fn instrumentation(mut self, enabled: bool) -> Self {
+        self.instrumentation = enabled;
+        self
+    }
+
+    /// Sets the clock.
+    ///
+    /// Defaults to `SystemClock`.
+    ///
+    /// The clock is used for histogram windowing, scheduling observers, timing delta snapshots,
+    /// and by the timing guards of every `Sink`.  Configuring a `MockClock` allows tests to control
+    /// all of these precisely, without sleeping.
+    ///
+    /// Timing samples sent directly, with their own instants, are unaffected.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Create a `Receiver` based on this configuration.
     pub fn build(self) -> Receiver<T> {
         Receiver::from_config(self)
     }
 }
+
+/// What a `Sink` does when there is no buffer available to hold a sample.
+///
+/// Samples dropped by the `DropNewest` and `DropOldest` policies are counted, and the total is
+/// exposed as the `hotmic_dropped_samples` internal metric when instrumentation is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Blocks until a buffer is available.
+    Block,
+
+    /// Drops the sample being sent.
+    DropNewest,
+
+    /// Drops the oldest batch of samples still waiting to be processed by the receiver, and
+    /// reuses its buffer.  If there is no such batch, the sample being sent is dropped instead.
+    DropOldest,
+
+    /// Allocates a new buffer.
+    ///
+    /// The buffer pool, and data channel, are unbounded with this policy: memory usage will grow
+    /// as long as samples are sent faster than the receiver can process them.
+    Grow,
+}
+
+/// What happens when registering a metric key would exceed a cardinality limit.
+///
+/// Registrations that don't get a key of their own -- those rejected, or folded into the overflow
+/// key -- are counted, and the total is exposed as the `hotmic_rejected_registrations` internal
+/// metric when instrumentation is enabled.  Keys evicted to make room are exposed as
+/// `hotmic_evicted_keys`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CardinalityPolicy<T> {
+    /// Rejects the registration: samples for the key are ignored.
+    Reject,
+
+    /// Evicts the key that was least recently updated, discarding its values, to make room.
+    ///
+    /// Samples for an evicted key are ignored unless it is registered again.
+    EvictLru,
+
+    /// Folds the key into the given overflow key: samples for the key are recorded against the
+    /// overflow key instead.
+    ///
+    /// The overflow key doesn't count towards the limit.
+    Overflow(T),
+}
+
+/// A configuration builder for the histograms backing percentile facets.
+///
+/// Used both for the defaults of a `Receiver`, via `Configuration`, and for individual metrics,
+/// via `Receiver::add_facet_with_histogram` and `Sink::add_facet_with_histogram`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramConfiguration {
+    pub(crate) window: Duration,
+    pub(crate) granularity: Duration,
+    pub(crate) significant_figures: u8,
+    pub(crate) lowest: u64,
+    pub(crate) highest: u64,
+    pub(crate) percentiles: Option<Vec<Percentile>>,
+    pub(crate) scale: f64,
+    pub(crate) encoded: bool,
+}
+
+impl Default for HistogramConfiguration {
+    fn default() -> HistogramConfiguration {
+        HistogramConfiguration {
+            window: Duration::from_secs(10),
+            granularity: Duration::from_secs(1),
+            significant_figures: 3,
+            lowest: 1,
+            highest: u64::MAX,
+            percentiles: None,
+            scale: 1.0,
+            encoded: false,
+        }
+    }
+}
+
+impl HistogramConfiguration {
+    /// Creates a new `HistogramConfiguration` with default values.
+    pub fn new() -> HistogramConfiguration {
+        Default::default()
+    }
+
+    /// Sets the window.
+    ///
+    /// Defaults to `10s`.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets the granularity.
+    ///
+    /// Defaults to `1s`.
+    ///
+    /// A histogram keeps a bucket for every period of this length within the window, up to 128
+    /// buckets: a granularity finer than that allows is coarsened to fit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `granularity` is zero.
+    pub fn granularity(mut self, granularity: Duration) -> Self {
+        assert!(granularity > Duration::from_secs(0), "histogram granularity must be non-zero");
+        self.granularity = granularity;
+        self
+    }
+
+    /// Sets the number of significant figures.
+    ///
+    /// Defaults to `3`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `figures` is greater than 5.
+    pub fn significant_figures(mut self, figures: u8) -> Self {
+        assert!(figures <= 5, "histograms support at most 5 significant figures");
+        self.significant_figures = figures;
+        self
+    }
+
+    /// Sets the lowest discernible value and the highest trackable value.
+    ///
+    /// Defaults to `1` and `u64::MAX`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `lowest` is zero, or if `highest` is less than twice `lowest`.
+    pub fn bounds(mut self, lowest: u64, highest: u64) -> Self {
+        assert!(lowest >= 1, "histogram lowest discernible value must be at least 1");
+        assert!(highest >= lowest.saturating_mul(2), "histogram highest trackable value must be at least twice the lowest");
+        self.lowest = lowest;
+        self.highest = highest;
+        self
+    }
+
+    /// Sets the percentiles.
+    ///
+    /// Defaults to the percentiles set via `Configuration::percentiles`.
+    pub fn percentiles(mut self, percentiles: Vec<Percentile>) -> Self {
+        self.percentiles = Some(percentiles);
+        self
+    }
+
+    /// Sets the scale that value samples are multiplied by when recorded.
+    ///
+    /// Defaults to `1.0`.
+    ///
+    /// Histograms can only record non-negative integers, so values are multiplied by the scale, and
+    /// rounded, before being recorded: a scale of `1000.0` keeps three decimal places of a
+    /// floating-point value.  Values that are still negative are recorded as zero, so offsetting
+    /// them beforehand is up to the caller.  Value percentiles report the scale they were recorded
+    /// with, via `Summary::scale`, and exporters divide it back out.  Timing samples are not scaled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` is not a positive, finite number.
+    pub fn scale(mut self, scale: f64) -> Self {
+        assert!(scale > 0.0 && scale.is_finite(), "histogram scale must be positive and finite");
+        self.scale = scale;
+        self
+    }
+
+    /// Sets whether or not summaries include the histogram they were extracted from.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// Percentiles from several hosts can't be averaged into a fleet-wide percentile.  With this
+    /// enabled, every summary also holds its histogram, encoded in the standard HdrHistogram V2
+    /// compressed format, available via `Summary::encoded`: these can be merged with
+    /// `MergedHistogram`, and percentiles computed from the result.  Encoding makes taking a
+    /// snapshot more expensive, and the snapshot larger.
+    pub fn encoded(mut self, encoded: bool) -> Self {
+        self.encoded = encoded;
+        self
+    }
+}
+
+/// A configuration builder for auto-registering facets.
+///
+/// Controls which facets are registered for a metric key the first time a sample is seen for it,
+/// based on the kind of sample, and how many keys can be registered this way in total.  Once the
+/// limit is reached, samples for unknown keys are ignored again.
+///
+/// Keys that have facets registered explicitly are never auto-registered.  Once every facet of a
+/// key has been removed, or evicted, the key is unknown again, and the next sample for it
+/// auto-registers it, as long as the limit hasn't been reached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoRegistration {
+    pub(crate) timing: Vec<FacetKind>,
+    pub(crate) count: Vec<FacetKind>,
+    pub(crate) value: Vec<FacetKind>,
+    pub(crate) limit: usize,
+}
+
+impl Default for AutoRegistration {
+    fn default() -> AutoRegistration {
+        AutoRegistration {
+            timing: vec![FacetKind::Count, FacetKind::TimingPercentile],
+            count: vec![FacetKind::Count],
+            value: vec![FacetKind::Gauge],
+            limit: 1024,
+        }
+    }
+}
+
+impl AutoRegistration {
+    /// Creates a new `AutoRegistration` with default values.
+    pub fn new() -> AutoRegistration {
+        Default::default()
+    }
+
+    /// Sets the facets registered for keys first seen with a timing sample.
+    ///
+    /// Defaults to count and timing percentiles.
+    pub fn timing(mut self, facets: Vec<FacetKind>) -> Self {
+        self.timing = facets;
+        self
+    }
+
+    /// Sets the facets registered for keys first seen with a count sample.
+    ///
+    /// Defaults to count.
+    pub fn count(mut self, facets: Vec<FacetKind>) -> Self {
+        self.count = facets;
+        self
+    }
+
+    /// Sets the facets registered for keys first seen with a value, value delta, or floating-point
+    /// value sample.
+    ///
+    /// Defaults to gauge.
+    pub fn value(mut self, facets: Vec<FacetKind>) -> Self {
+        self.value = facets;
+        self
+    }
+
+    /// Sets the maximum number of keys that can be auto-registered.
+    ///
+    /// Defaults to `1024`.
+    ///
+    /// Keys that none of their facets could be registered for, such as those rejected by a
+    /// cardinality limit, don't count towards this limit.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Gets the facets to register for a key first seen with the given sample.
+    pub(crate) fn facets<T>(&self, sample: &Sample<T>) -> &[FacetKind] {
+        match *sample {
+            Sample::Timing(..) | Sample::TimingDuration(..) => &self.timing,
+            Sample::Count(..) => &self.count,
+            Sample::Value(..) | Sample::ValueDelta(..) | Sample::FloatValue(..) => &self.value,
+        }
+    }
+}
@@ -1,42 +1,99 @@
-use std::io;
 use std::sync::mpsc;
-use helper::io_error;
-use channel::{Sender, SendError};
+use std::time::Duration;
+use channel::Sender;
+use error::Error;
+use configuration::HistogramConfiguration;
 use data::{Facet, Snapshot};
 
 pub(crate) enum ControlMessage<T> {
-    AddFacet(Facet<T>),
+    AddFacet(Facet<T>, Option<HistogramConfiguration>),
     RemoveFacet(Facet<T>),
     Snapshot(mpsc::SyncSender<Snapshot<T>>),
+    DeltaSnapshot(String, mpsc::SyncSender<Snapshot<T>>),
+    Unsubscribe(String),
+    Shutdown(mpsc::SyncSender<Snapshot<T>>),
 }
 
 /// Dedicated handle for performing operations on a running `Receiver`.
 ///
 /// The caller is able to request metric snapshots at any time without requiring mutable access to
 /// the sink.  This all flows through the existing control mechanism, and so is very fast.
+///
+/// `Controller` is cloneable, so multiple callers can each hold their own handle.
 pub struct Controller<T> {
     control_tx: Sender<ControlMessage<T>>,
+    timeout: Option<Duration>,
+}
+
+impl<T> Clone for Controller<T> {
+    fn clone(&self) -> Controller<T> {
+        Controller {
+            control_tx: self.control_tx.clone(),
+            timeout: self.timeout,
+        }
+    }
 }
 
 impl<T> Controller<T> {
-    pub(crate) fn new(control_tx: Sender<ControlMessage<T>>) -> Controller<T> {
-        Controller { control_tx: control_tx }
+    pub(crate) fn new(control_tx: Sender<ControlMessage<T>>, timeout: Option<Duration>) -> Controller<T> {
+        Controller { control_tx, timeout }
     }
 
     /// Retrieves a snapshot of the current metric state.
-    pub fn get_snapshot(&self) -> Result<Snapshot<T>, io::Error> {
+    ///
+    /// Fails with `Error::ReceiverGone` if the receiver has been shut down, and with
+    /// `Error::SnapshotTimeout` if it does not respond within the configured snapshot timeout.
+    pub fn get_snapshot(&self) -> Result<Snapshot<T>, Error> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.request(ControlMessage::Snapshot(tx), rx)
+    }
+
+    /// Retrieves a snapshot of the current metric state, along with the change in every counter
+    /// since the previous delta snapshot retrieved for `subscriber`.
+    ///
+    /// Each subscriber is tracked independently, by name, so any number of consumers can compute
+    /// their own intervals without coordinating.  The first delta snapshot for a subscriber covers
+    /// everything since the receiver was created.
+    ///
+    /// Fails in the same way as `get_snapshot`.  If a request fails, the subscriber's next delta
+    /// snapshot may or may not cover the interval of the failed request.
+    pub fn get_delta_snapshot(&self, subscriber: &str) -> Result<Snapshot<T>, Error> {
         let (tx, rx) = mpsc::sync_channel(1);
-        let msg = ControlMessage::Snapshot(tx);
-
-        match self.control_tx.send(msg) {
-            Ok(_) => match rx.recv() {
-                Ok(result) => Ok(result),
-                Err(_) => Err(io_error("failed to receive snapshot")),
-            },
-            Err(e) => match e {
-                SendError::Io(e) => Err(e),
-                SendError::Full(_) | SendError::Disconnected(_) => Err(io_error("failed to send snapshot command")),
-            },
+        self.request(ControlMessage::DeltaSnapshot(subscriber.to_owned(), tx), rx)
+    }
+
+    /// Stops tracking `subscriber` for delta snapshots.
+    ///
+    /// The receiver holds on to the counter values seen by every subscriber, so subscribers that
+    /// are done with delta snapshots should unsubscribe.  If `subscriber` retrieves a delta
+    /// snapshot again, it is treated as a new subscriber.
+    ///
+    /// Fails with `Error::ReceiverGone` if the receiver has been shut down.
+    pub fn unsubscribe(&self, subscriber: &str) -> Result<(), Error> {
+        self.control_tx.send(ControlMessage::Unsubscribe(subscriber.to_owned()))?;
+        Ok(())
+    }
+
+    /// Shuts down the receiver.
+    ///
+    /// The receiver will drain and process all samples that have already been sent before taking
+    /// a final snapshot, which is returned, and then `Receiver::run` will return.
+    ///
+    /// Samples still buffered in a `Sink` are not sent: call `Sink::flush`, or drop the sink,
+    /// beforehand to make sure they're included.
+    pub fn shutdown(&self) -> Result<Snapshot<T>, Error> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.request(ControlMessage::Shutdown(tx), rx)
+    }
+
+    fn request(&self, msg: ControlMessage<T>, rx: mpsc::Receiver<Snapshot<T>>) -> Result<Snapshot<T>, Error> {
+        self.control_tx.send(msg)?;
+        match self.timeout {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => Error::SnapshotTimeout,
+                mpsc::RecvTimeoutError::Disconnected => Error::ReceiverGone,
+            }),
+            None => rx.recv().map_err(|_| Error::ReceiverGone),
         }
     }
 }
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use fnv::{FnvHashMap, FnvHashSet};
+use configuration::CardinalityPolicy;
+use super::Labels;
+
+/// The outcome of registering a new metric key against a `Limiter`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Registration<T> {
+    /// The key was registered.
+    Registered,
+
+    /// The key was not registered, as the limit has been reached.
+    Rejected,
+
+    /// The key was registered, in place of the given least-recently-updated key.
+    Evicted(T),
+
+    /// The key was not registered, and its samples will be folded into the given overflow key.
+    Overflowed(T),
+}
+
+/// Enforces a limit on the number of metric keys held by a counter, gauge or histogram, and on the
+/// number of labeled series held by each key.
+///
+/// When no limit is set, every key is registered, and nothing is tracked.
+pub(crate) struct Limiter<T> {
+    limit: Option<usize>,
+    series_limit: Option<usize>,
+    policy: CardinalityPolicy<T>,
+    tick: u64,
+    keys: FnvHashMap<T, u64>,
+    // Keys by when they were last updated, so the least recently updated one is always first.
+    order: BTreeMap<u64, T>,
+    overflowed: FnvHashSet<T>,
+    rejected_series: usize,
+}
+
+impl<T> Limiter<T>
+    where T: Eq + Hash + Clone
+{
+    pub fn new(limit: Option<usize>, policy: CardinalityPolicy<T>) -> Limiter<T> {
+        Limiter {
+            limit,
+            series_limit: None,
+            policy,
+            tick: 0,
+            keys: FnvHashMap::default(),
+            order: BTreeMap::new(),
+            overflowed: FnvHashSet::default(),
+            rejected_series: 0,
+        }
+    }
+
+    /// Limits the number of labeled series each key can hold.
+    pub fn with_series_limit(mut self, limit: Option<usize>) -> Limiter<T> {
+        self.series_limit = limit;
+        self
+    }
+
+    pub fn unlimited() -> Limiter<T> {
+        Limiter::new(None, CardinalityPolicy::Reject)
+    }
+
+    /// Decides whether or not a key, which isn't registered yet, can be registered.
+    ///
+    /// The overflow key itself is always registered, and doesn't count towards the limit.
+    pub fn admit(&mut self, key: &T) -> Registration<T> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Registration::Registered,
+        };
+
+        if let CardinalityPolicy::Overflow(ref overflow) = self.policy {
+            if overflow == key {
+                return Registration::Registered;
+            }
+        }
+
+        if self.keys.len() < limit {
+            self.track(key);
+            return Registration::Registered;
+        }
+
+        match self.policy {
+            CardinalityPolicy::Reject => Registration::Rejected,
+            CardinalityPolicy::EvictLru => {
+                let oldest = self.order.keys().next().cloned();
+                match oldest.and_then(|tick| self.order.remove(&tick)) {
+                    Some(evicted) => {
+                        let _ = self.keys.remove(&evicted);
+                        self.track(key);
+                        Registration::Evicted(evicted)
+                    },
+                    // A limit of zero leaves nothing to evict.
+                    None => Registration::Rejected,
+                }
+            },
+            CardinalityPolicy::Overflow(ref overflow) => {
+                self.overflowed.insert(key.clone());
+                Registration::Overflowed(overflow.clone())
+            },
+        }
+    }
+
+    /// Marks a registered key as updated.
+    pub fn touch(&mut self, key: &T) {
+        if self.policy == CardinalityPolicy::EvictLru {
+            if let Some(tick) = self.keys.get_mut(key) {
+                self.tick += 1;
+                let previous = ::std::mem::replace(tick, self.tick);
+                if let Some(key) = self.order.remove(&previous) {
+                    self.order.insert(self.tick, key);
+                }
+            }
+        }
+    }
+
+    /// Decides whether or not a key can hold a series with the given labels, given the series it
+    /// already holds.
+    ///
+    /// Series already held, and the series without labels, are always admitted.  Series rejected
+    /// here are counted, and their samples should be ignored.
+    pub fn admit_series<V>(&mut self, series: &FnvHashMap<Labels, V>, labels: &Labels) -> bool {
+        let limit = match self.series_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        if labels.is_empty() || series.contains_key(labels) {
+            return true;
+        }
+
+        let unlabeled = if series.contains_key(&Labels::new()) { 1 } else { 0 };
+        if series.len() - unlabeled < limit {
+            true
+        } else {
+            self.rejected_series += 1;
+            false
+        }
+    }
+
+    /// Gets the number of series rejected for exceeding the series limit.
+    pub fn rejected_series(&self) -> usize {
+        self.rejected_series
+    }
+
+    /// Gets the overflow key that samples for the given key are folded into, if any.
+    pub fn overflow_for(&self, key: &T) -> Option<&T> {
+        match self.policy {
+            CardinalityPolicy::Overflow(ref overflow) if self.overflowed.contains(key) => Some(overflow),
+            _ => None,
+        }
+    }
+
+    /// Forgets about a key that has been deregistered.
+    pub fn forget(&mut self, key: &T) {
+        if let Some(tick) = self.keys.remove(key) {
+            let _ = self.order.remove(&tick);
+        }
+        let _ = self.overflowed.remove(key);
+    }
+
+    fn track(&mut self, key: &T) {
+        self.tick += 1;
+        self.keys.insert(key.clone(), self.tick);
+        self.order.insert(self.tick, key.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limiter, Registration};
+    use configuration::CardinalityPolicy;
+    use data::Labels;
+    use fnv::FnvHashMap;
+
+    #[test]
+    fn test_limiter_unlimited() {
+        let mut limiter = Limiter::unlimited();
+        for i in 0..100 {
+            assert_eq!(limiter.admit(&i), Registration::Registered);
+        }
+        assert!(limiter.keys.is_empty());
+        assert!(limiter.order.is_empty());
+    }
+
+    #[test]
+    fn test_limiter_reject() {
+        let mut limiter = Limiter::new(Some(2), CardinalityPolicy::Reject);
+        assert_eq!(limiter.admit(&1), Registration::Registered);
+        assert_eq!(limiter.admit(&2), Registration::Registered);
+        assert_eq!(limiter.admit(&3), Registration::Rejected);
+
+        limiter.forget(&1);
+        assert_eq!(limiter.admit(&3), Registration::Registered);
+    }
+
+    #[test]
+    fn test_limiter_evict_lru() {
+        let mut limiter = Limiter::new(Some(2), CardinalityPolicy::EvictLru);
+        assert_eq!(limiter.admit(&1), Registration::Registered);
+        assert_eq!(limiter.admit(&2), Registration::Registered);
+
+        // Key 1 was updated most recently, so key 2 goes first.
+        limiter.touch(&1);
+        assert_eq!(limiter.admit(&3), Registration::Evicted(2));
+        assert_eq!(limiter.admit(&4), Registration::Evicted(1));
+
+        // Forgotten keys are never evicted.
+        limiter.forget(&3);
+        assert_eq!(limiter.admit(&5), Registration::Registered);
+        assert_eq!(limiter.admit(&6), Registration::Evicted(4));
+        assert_eq!(limiter.keys.len(), limiter.order.len());
+
+        let mut empty = Limiter::new(Some(0), CardinalityPolicy::EvictLru);
+        assert_eq!(empty.admit(&1), Registration::Rejected);
+    }
+
+    #[test]
+    fn test_limiter_overflow() {
+        let mut limiter = Limiter::new(Some(1), CardinalityPolicy::Overflow(0));
+        assert_eq!(limiter.admit(&1), Registration::Registered);
+        assert_eq!(limiter.admit(&2), Registration::Overflowed(0));
+        assert_eq!(limiter.admit(&0), Registration::Registered);
+
+        assert_eq!(limiter.overflow_for(&1), None);
+        assert_eq!(limiter.overflow_for(&2), Some(&0));
+
+        limiter.forget(&2);
+        assert_eq!(limiter.overflow_for(&2), None);
+    }
+
+    #[test]
+    fn test_limiter_series() {
+        let mut limiter: Limiter<u64> = Limiter::unlimited().with_series_limit(Some(1));
+        let (a, b) = (Labels::new().with("user", "a"), Labels::new().with("user", "b"));
+
+        let mut series = FnvHashMap::default();
+        series.insert(Labels::new(), 0);
+        assert!(limiter.admit_series(&series, &a));
+        series.insert(a.clone(), 0);
+
+        // The unlabeled series doesn't count, and series already held are always admitted.
+        assert!(!limiter.admit_series(&series, &b));
+        assert!(limiter.admit_series(&series, &a));
+        assert!(limiter.admit_series(&series, &Labels::new()));
+        assert_eq!(limiter.rejected_series(), 1);
+    }
+}
@@ -1,57 +1,115 @@
 use std::hash::Hash;
 use fnv::FnvHashMap;
-use super::Sample;
+use super::{Labels, Limiter, Registration, Sample};
 
 pub struct Counter<T> {
-    pub data: FnvHashMap<T, i64>,
+    data: FnvHashMap<T, FnvHashMap<Labels, i64>>,
+    limiter: Limiter<T>,
 }
 
 impl<T> Counter<T>
-    where T: Eq + Hash
+    where T: Eq + Hash + Clone
 {
     pub fn new() -> Counter<T> {
-        Counter { data: FnvHashMap::default() }
+        Counter {
+            data: FnvHashMap::default(),
+            limiter: Limiter::unlimited(),
+        }
     }
 
-    pub fn register(&mut self, key: T) {
-        let _ = self.data.entry(key).or_insert(0);
+    /// Limits the number of keys that can be registered.
+    pub fn with_limiter(mut self, limiter: Limiter<T>) -> Counter<T> {
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn register(&mut self, key: T) -> Registration<T> {
+        if self.data.contains_key(&key) {
+            return Registration::Registered;
+        }
+
+        let registration = self.limiter.admit(&key);
+        match registration {
+            Registration::Registered => self.insert(key),
+            Registration::Evicted(ref evicted) => {
+                let _ = self.data.remove(evicted);
+                self.insert(key);
+            },
+            Registration::Overflowed(ref overflow) => self.insert(overflow.clone()),
+            Registration::Rejected => {},
+        }
+        registration
     }
 
     pub fn deregister(&mut self, key: T) {
         let _ = self.data.remove(&key);
+        self.limiter.forget(&key);
     }
 
-    pub fn update(&mut self, sample: &Sample<T>) {
-        match sample {
-            Sample::Timing(key, _, _, count) => {
-                let coerced = *count as i64;
-                if let Some(entry) = self.data.get_mut(&key) {
-                    *entry += coerced;
-                }
-            },
-            Sample::Count(key, count) => {
-                if let Some(entry) = self.data.get_mut(&key) {
-                    *entry += *count;
-                }
-            },
-            Sample::Value(key, _) => {
-                if let Some(entry) = self.data.get_mut(&key) {
-                    *entry += 1;
+    fn insert(&mut self, key: T) {
+        let _ = self.data.entry(key).or_insert_with(|| {
+            let mut series = FnvHashMap::default();
+            series.insert(Labels::new(), 0);
+            series
+        });
+    }
+
+    pub fn update(&mut self, sample: &Sample<T>, labels: &Labels) {
+        let (key, delta) = match sample {
+            Sample::Timing(key, _, _, count) => (key, *count as i64),
+            Sample::TimingDuration(key, _, count) => (key, *count as i64),
+            Sample::Count(key, count) => (key, *count),
+            Sample::Value(key, _) => (key, 1),
+            Sample::ValueDelta(key, _) => (key, 1),
+            Sample::FloatValue(key, _) => (key, 1),
+        };
+
+        if let Some(series) = self.data.get_mut(key) {
+            self.limiter.touch(key);
+            if self.limiter.admit_series(series, labels) {
+                update_series(series, labels, delta);
+            }
+        } else if let Some(overflow) = self.limiter.overflow_for(key).cloned() {
+            if let Some(series) = self.data.get_mut(&overflow) {
+                if self.limiter.admit_series(series, labels) {
+                    update_series(series, labels, delta);
                 }
-            },
+            }
+        }
+    }
+
+    /// Gets the number of registered keys.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Gets the number of labeled series rejected for exceeding the series limit.
+    pub fn rejected_series(&self) -> usize {
+        self.limiter.rejected_series()
+    }
+
+    pub fn values(&self, key: &T) -> Vec<(Labels, i64)> {
+        match self.data.get(key) {
+            Some(series) => series.iter().map(|(labels, value)| (labels.clone(), *value)).collect(),
+            None => Vec::new(),
         }
     }
+}
 
-    pub fn value(&self, key: T) -> i64 {
-        *self.data.get(&key).unwrap_or(&0)
+fn update_series(series: &mut FnvHashMap<Labels, i64>, labels: &Labels, delta: i64) {
+    if let Some(entry) = series.get_mut(labels) {
+        *entry += delta;
+        return;
     }
+    series.insert(labels.clone(), delta);
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::Instant;
     use super::Counter;
-    use data::Sample;
+    use configuration::CardinalityPolicy;
+    use data::{Labels, Limiter, Registration, Sample};
 
     #[test]
     fn test_counter_unregistered_update() {
@@ -59,10 +117,10 @@ mod tests {
 
         let key = "foo".to_owned();
         let sample = Sample::Count(key.clone(), 42);
-        counter.update(&sample);
+        counter.update(&sample, &Labels::new());
 
-        let value = counter.value(key);
-        assert_eq!(value, 0);
+        let values = counter.values(&key);
+        assert!(values.is_empty());
     }
 
     #[test]
@@ -73,10 +131,10 @@ mod tests {
         counter.register(key.clone());
 
         let sample = Sample::Count(key.clone(), 42);
-        counter.update(&sample);
+        counter.update(&sample, &Labels::new());
 
-        let value = counter.value(key);
-        assert_eq!(value, 42);
+        let values = counter.values(&key);
+        assert_eq!(values, vec![(Labels::new(), 42)]);
     }
 
     #[test]
@@ -88,29 +146,66 @@ mod tests {
         counter.register(ckey.clone());
 
         let csample = Sample::Count(ckey.clone(), 42);
-        counter.update(&csample);
+        counter.update(&csample, &Labels::new());
 
-        let cvalue = counter.value(ckey);
-        assert_eq!(cvalue, 42);
+        let cvalues = counter.values(&ckey);
+        assert_eq!(cvalues, vec![(Labels::new(), 42)]);
 
         // Timing samples.
         let tkey = "tkey".to_owned();
         counter.register(tkey.clone());
 
         let tsample = Sample::Timing(tkey.clone(), Instant::now(), Instant::now(), 73);
-        counter.update(&tsample);
+        counter.update(&tsample, &Labels::new());
 
-        let tvalue = counter.value(tkey);
-        assert_eq!(tvalue, 73);
+        let tvalues = counter.values(&tkey);
+        assert_eq!(tvalues, vec![(Labels::new(), 73)]);
 
         // Value samples.
         let vkey = "vkey".to_owned();
         counter.register(vkey.clone());
 
         let vsample = Sample::Value(vkey.clone(), 22);
-        counter.update(&vsample);
+        counter.update(&vsample, &Labels::new());
 
-        let vvalue = counter.value(vkey);
-        assert_eq!(vvalue, 1);
+        let vvalues = counter.values(&vkey);
+        assert_eq!(vvalues, vec![(Labels::new(), 1)]);
+    }
+
+    #[test]
+    fn test_counter_labeled_update() {
+        let mut counter = Counter::new();
+
+        let key = "foo".to_owned();
+        counter.register(key.clone());
+
+        let a = Labels::new().with("endpoint", "/a");
+        let b = Labels::new().with("endpoint", "/b");
+        counter.update(&Sample::Count(key.clone(), 1), &a);
+        counter.update(&Sample::Count(key.clone(), 2), &b);
+        counter.update(&Sample::Count(key.clone(), 3), &a);
+
+        let mut values = counter.values(&key);
+        values.sort();
+        assert_eq!(values, vec![(Labels::new(), 0), (a, 4), (b, 2)]);
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let overflow = "overflow".to_owned();
+        let limiter = Limiter::new(Some(1), CardinalityPolicy::Overflow(overflow.clone()));
+        let mut counter = Counter::new().with_limiter(limiter);
+
+        let (foo, bar) = ("foo".to_owned(), "bar".to_owned());
+        assert_eq!(counter.register(foo.clone()), Registration::Registered);
+        assert_eq!(counter.register(bar.clone()), Registration::Overflowed(overflow.clone()));
+
+        counter.update(&Sample::Count(foo.clone(), 1), &Labels::new());
+        counter.update(&Sample::Count(bar.clone(), 2), &Labels::new());
+        counter.update(&Sample::Count(bar.clone(), 3), &Labels::new());
+
+        assert_eq!(counter.values(&foo), vec![(Labels::new(), 1)]);
+        assert!(counter.values(&bar).is_empty());
+        assert_eq!(counter.values(&overflow), vec![(Labels::new(), 5)]);
     }
 }
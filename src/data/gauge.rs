@@ -1,47 +1,141 @@
 use std::hash::Hash;
 use fnv::FnvHashMap;
-use super::Sample;
+use super::{Labels, Limiter, Registration, Sample};
 
 pub struct Gauge<T> {
-    data: FnvHashMap<T, u64>,
+    data: FnvHashMap<T, FnvHashMap<Labels, Level>>,
+    limiter: Limiter<T>,
 }
 
 impl<T> Gauge<T>
-    where T: Eq + Hash
+    where T: Eq + Hash + Clone
 {
     pub fn new() -> Gauge<T> {
-        Gauge { data: FnvHashMap::default() }
+        Gauge {
+            data: FnvHashMap::default(),
+            limiter: Limiter::unlimited(),
+        }
+    }
+
+    /// Limits the number of keys that can be registered.
+    pub fn with_limiter(mut self, limiter: Limiter<T>) -> Gauge<T> {
+        self.limiter = limiter;
+        self
     }
 
-    pub fn register(&mut self, key: T) {
-        let _ = self.data.entry(key).or_insert(0);
+    pub fn register(&mut self, key: T) -> Registration<T> {
+        if self.data.contains_key(&key) {
+            return Registration::Registered;
+        }
+
+        let registration = self.limiter.admit(&key);
+        match registration {
+            Registration::Registered => self.insert(key),
+            Registration::Evicted(ref evicted) => {
+                let _ = self.data.remove(evicted);
+                self.insert(key);
+            },
+            Registration::Overflowed(ref overflow) => self.insert(overflow.clone()),
+            Registration::Rejected => {},
+        }
+        registration
     }
 
     pub fn deregister(&mut self, key: T) {
         let _ = self.data.remove(&key);
+        self.limiter.forget(&key);
+    }
+
+    fn insert(&mut self, key: T) {
+        let _ = self.data.entry(key).or_insert_with(|| {
+            let mut series = FnvHashMap::default();
+            series.insert(Labels::new(), Level::Signed(0));
+            series
+        });
     }
 
-    pub fn update(&mut self, sample: &Sample<T>) {
-        match sample {
-            Sample::Value(key, value) => {
-                if let Some(entry) = self.data.get_mut(&key) {
-                    *entry = *value;
+    pub fn update(&mut self, sample: &Sample<T>, labels: &Labels) {
+        // Absolute values beyond what a signed gauge can hold are clamped: the receiver counts them.
+        let (key, update) = match sample {
+            Sample::Value(key, value) => (key, Update::Set(Level::Signed((*value).min(i64::MAX as u64) as i64))),
+            Sample::ValueDelta(key, delta) => (key, Update::Add(*delta)),
+            Sample::FloatValue(key, value) => (key, Update::Set(Level::Float(*value))),
+            _ => return,
+        };
+
+        if let Some(series) = self.data.get_mut(key) {
+            self.limiter.touch(key);
+            if self.limiter.admit_series(series, labels) {
+                update_series(series, labels, update);
+            }
+        } else if let Some(overflow) = self.limiter.overflow_for(key).cloned() {
+            if let Some(series) = self.data.get_mut(&overflow) {
+                if self.limiter.admit_series(series, labels) {
+                    update_series(series, labels, update);
                 }
-            },
-            _ => {},
+            }
+        }
+    }
+
+    /// Gets the number of registered keys.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Gets the number of labeled series rejected for exceeding the series limit.
+    pub fn rejected_series(&self) -> usize {
+        self.limiter.rejected_series()
+    }
+
+    pub fn values(&self, key: &T) -> Vec<(Labels, Level)> {
+        match self.data.get(key) {
+            Some(series) => series.iter().map(|(labels, value)| (labels.clone(), *value)).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The current value of a gauge series.
+///
+/// Gauges hold exact, signed integers until a floating-point value is set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Level {
+    Signed(i64),
+    Float(f64),
+}
+
+impl Level {
+    fn apply(self, update: Update) -> Level {
+        match (self, update) {
+            (_, Update::Set(level)) => level,
+            (Level::Signed(value), Update::Add(delta)) => Level::Signed(value.saturating_add(delta)),
+            (Level::Float(value), Update::Add(delta)) => Level::Float(value + delta as f64),
         }
     }
+}
+
+/// How a sample changes a gauge.
+#[derive(Clone, Copy)]
+enum Update {
+    Set(Level),
+    Add(i64),
+}
 
-    pub fn value(&self, key: T) -> u64 {
-        *self.data.get(&key).unwrap_or(&0)
+fn update_series(series: &mut FnvHashMap<Labels, Level>, labels: &Labels, update: Update) {
+    if let Some(entry) = series.get_mut(labels) {
+        *entry = entry.apply(update);
+        return;
     }
+
+    series.insert(labels.clone(), Level::Signed(0).apply(update));
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::Instant;
-    use super::Gauge;
-    use data::Sample;
+    use super::{Gauge, Level};
+    use configuration::CardinalityPolicy;
+    use data::{Labels, Limiter, Registration, Sample};
 
     #[test]
     fn test_gauge_unregistered_update() {
@@ -49,10 +143,10 @@ mod tests {
 
         let key = "foo".to_owned();
         let sample = Sample::Value(key.clone(), 42);
-        gauge.update(&sample);
+        gauge.update(&sample, &Labels::new());
 
-        let value = gauge.value(key);
-        assert_eq!(value, 0);
+        let values = gauge.values(&key);
+        assert!(values.is_empty());
     }
 
     #[test]
@@ -63,10 +157,10 @@ mod tests {
         gauge.register(key.clone());
 
         let sample = Sample::Value(key.clone(), 42);
-        gauge.update(&sample);
+        gauge.update(&sample, &Labels::new());
 
-        let value = gauge.value(key);
-        assert_eq!(value, 42);
+        let values = gauge.values(&key);
+        assert_eq!(values, vec![(Labels::new(), Level::Signed(42))]);
     }
 
     #[test]
@@ -78,29 +172,111 @@ mod tests {
         gauge.register(ckey.clone());
 
         let csample = Sample::Count(ckey.clone(), 42);
-        gauge.update(&csample);
+        gauge.update(&csample, &Labels::new());
 
-        let cvalue = gauge.value(ckey);
-        assert_eq!(cvalue, 0);
+        let cvalues = gauge.values(&ckey);
+        assert_eq!(cvalues, vec![(Labels::new(), Level::Signed(0))]);
 
         // Timing samples.
         let tkey = "tkey".to_owned();
         gauge.register(tkey.clone());
 
         let tsample = Sample::Timing(tkey.clone(), Instant::now(), Instant::now(), 73);
-        gauge.update(&tsample);
+        gauge.update(&tsample, &Labels::new());
 
-        let tvalue = gauge.value(tkey);
-        assert_eq!(tvalue, 0);
+        let tvalues = gauge.values(&tkey);
+        assert_eq!(tvalues, vec![(Labels::new(), Level::Signed(0))]);
 
         // Value samples.
         let vkey = "vkey".to_owned();
         gauge.register(vkey.clone());
 
         let vsample = Sample::Value(vkey.clone(), 22);
-        gauge.update(&vsample);
+        gauge.update(&vsample, &Labels::new());
+
+        let vvalues = gauge.values(&vkey);
+        assert_eq!(vvalues, vec![(Labels::new(), Level::Signed(22))]);
+    }
+
+    #[test]
+    fn test_gauge_labeled_update() {
+        let mut gauge = Gauge::new();
+
+        let key = "foo".to_owned();
+        gauge.register(key.clone());
+
+        let a = Labels::new().with("shard", "1");
+        gauge.update(&Sample::Value(key.clone(), 5), &a);
+        gauge.update(&Sample::Value(key.clone(), 9), &a);
+
+        let mut values = gauge.values(&key);
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values, vec![(Labels::new(), Level::Signed(0)), (a, Level::Signed(9))]);
+    }
+
+    #[test]
+    fn test_gauge_evict_lru() {
+        let mut gauge = Gauge::new().with_limiter(Limiter::new(Some(2), CardinalityPolicy::EvictLru));
+
+        let (a, b, c) = ("a".to_owned(), "b".to_owned(), "c".to_owned());
+        gauge.register(a.clone());
+        gauge.register(b.clone());
+        gauge.update(&Sample::Value(a.clone(), 1), &Labels::new());
+
+        assert_eq!(gauge.register(c.clone()), Registration::Evicted(b.clone()));
+        gauge.update(&Sample::Value(b.clone(), 2), &Labels::new());
+        gauge.update(&Sample::Value(c.clone(), 3), &Labels::new());
+
+        assert_eq!(gauge.values(&a), vec![(Labels::new(), Level::Signed(1))]);
+        assert!(gauge.values(&b).is_empty());
+        assert_eq!(gauge.values(&c), vec![(Labels::new(), Level::Signed(3))]);
+    }
+
+    #[test]
+    fn test_gauge_deltas() {
+        let mut gauge = Gauge::new();
+
+        let key = "foo".to_owned();
+        gauge.register(key.clone());
+
+        gauge.update(&Sample::ValueDelta(key.clone(), 5), &Labels::new());
+        gauge.update(&Sample::ValueDelta(key.clone(), -8), &Labels::new());
+        assert_eq!(gauge.values(&key), vec![(Labels::new(), Level::Signed(-3))]);
+
+        // Absolute values replace the current value, and deltas apply on top.
+        gauge.update(&Sample::Value(key.clone(), 10), &Labels::new());
+        gauge.update(&Sample::ValueDelta(key.clone(), 1), &Labels::new());
+        assert_eq!(gauge.values(&key), vec![(Labels::new(), Level::Signed(11))]);
+
+        // New labeled series start from zero.
+        let labels = Labels::new().with("shard", "1");
+        gauge.update(&Sample::ValueDelta(key.clone(), -2), &labels);
+        let mut values = gauge.values(&key);
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values, vec![(Labels::new(), Level::Signed(11)), (labels, Level::Signed(-2))]);
+
+        // Values that don't fit are clamped, and deltas saturate.
+        gauge.update(&Sample::Value(key.clone(), u64::MAX), &Labels::new());
+        gauge.update(&Sample::ValueDelta(key.clone(), 1), &Labels::new());
+        let values = gauge.values(&key);
+        assert!(values.contains(&(Labels::new(), Level::Signed(i64::MAX))));
+    }
+
+    #[test]
+    fn test_gauge_float_values() {
+        let mut gauge = Gauge::new();
+
+        let key = "load".to_owned();
+        gauge.register(key.clone());
+
+        gauge.update(&Sample::FloatValue(key.clone(), -1.25), &Labels::new());
+        assert_eq!(gauge.values(&key), vec![(Labels::new(), Level::Float(-1.25))]);
+
+        // Deltas keep a floating-point gauge floating-point, until an integer value is set again.
+        gauge.update(&Sample::ValueDelta(key.clone(), 2), &Labels::new());
+        assert_eq!(gauge.values(&key), vec![(Labels::new(), Level::Float(0.75))]);
 
-        let vvalue = gauge.value(vkey);
-        assert_eq!(vvalue, 22);
+        gauge.update(&Sample::Value(key.clone(), 3), &Labels::new());
+        assert_eq!(gauge.values(&key), vec![(Labels::new(), Level::Signed(3))]);
     }
 }
@@ -1,69 +1,191 @@
 use std::hash::Hash;
+use std::sync::Arc;
 use fnv::FnvHashMap;
 use hdrhistogram::Histogram as HdrHistogram;
 use std::time::{Instant, Duration};
-use super::Sample;
+use clock::{Clock, SystemClock};
+use configuration::HistogramConfiguration;
+use super::{Labels, Limiter, Percentile, Registration, Sample};
 use helper::duration_as_nanos;
 
 pub struct Histogram<T> {
-    window: Duration,
-    granularity: Duration,
-    data: FnvHashMap<T, WindowedHistogram>,
+    conf: HistogramConfiguration,
+    data: FnvHashMap<T, Series>,
+    limiter: Limiter<T>,
+    clock: Arc<dyn Clock>,
+}
+
+/// All of the labeled histograms for a single metric key.
+struct Series {
+    conf: HistogramConfiguration,
+    histograms: FnvHashMap<Labels, WindowedHistogram>,
 }
 
 impl<T> Histogram<T>
-    where T: Eq + Hash
+    where T: Eq + Hash + Clone
 {
-    pub fn new(window: Duration, granularity: Duration) -> Histogram<T> {
+    pub fn new(conf: HistogramConfiguration) -> Histogram<T> {
         Histogram {
-            window: window,
-            granularity: granularity,
+            conf,
             data: FnvHashMap::default(),
+            limiter: Limiter::unlimited(),
+            clock: Arc::new(SystemClock),
         }
     }
 
-    pub fn register(&mut self, key: T) {
-        let _ = self.data.entry(key).or_insert(WindowedHistogram::new(
-            self.window.clone(), self.granularity.clone()
-        ));
+    /// Limits the number of keys that can be registered.
+    pub fn with_limiter(mut self, limiter: Limiter<T>) -> Histogram<T> {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Uses the given clock for the windowing of newly-created histograms.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Histogram<T> {
+        self.clock = clock;
+        self
+    }
+
+    /// Registers a metric key.
+    ///
+    /// If `conf` is not given, the default histogram configuration is used.  If the key is already
+    /// registered, its configuration is left unchanged.  An overflow key always uses the default
+    /// histogram configuration.
+    pub fn register(&mut self, key: T, conf: Option<HistogramConfiguration>) -> Registration<T> {
+        if self.data.contains_key(&key) {
+            return Registration::Registered;
+        }
+
+        let registration = self.limiter.admit(&key);
+        match registration {
+            Registration::Registered => self.insert(key, conf),
+            Registration::Evicted(ref evicted) => {
+                let _ = self.data.remove(evicted);
+                self.insert(key, conf);
+            },
+            Registration::Overflowed(ref overflow) => self.insert(overflow.clone(), None),
+            Registration::Rejected => {},
+        }
+        registration
     }
 
     pub fn deregister(&mut self, key: T) {
         let _ = self.data.remove(&key);
+        self.limiter.forget(&key);
     }
 
-    pub fn update(&mut self, sample: &Sample<T>) {
-        match sample {
-            Sample::Timing(key, start, end, _) => {
-                if let Some(entry) = self.data.get_mut(&key) {
-                    let delta = *end - *start;
-                    let delta = (delta.as_secs() * 1_000_000_000) + delta.subsec_nanos() as u64;
-                    entry.update(delta);
-                }
+    fn insert(&mut self, key: T, conf: Option<HistogramConfiguration>) {
+        let default = &self.conf;
+        let now = self.clock.now();
+        let _ = self.data.entry(key).or_insert_with(|| {
+            let conf = conf.unwrap_or_else(|| default.clone());
+            let mut histograms = FnvHashMap::default();
+            histograms.insert(Labels::new(), WindowedHistogram::new(&conf, now));
+            Series { conf, histograms }
+        });
+    }
+
+    pub fn update(&mut self, sample: &Sample<T>, labels: &Labels) {
+        let (key, value) = match sample {
+            Sample::Timing(key, start, end, _) => match end.checked_duration_since(*start) {
+                Some(delta) => (key, Input::Timing(duration_as_nanos(delta))),
+                // Reversed instants don't describe a timing at all.
+                None => return,
             },
-            Sample::Value(key, value) => {
-                if let Some(entry) = self.data.get_mut(&key) {
-                    entry.update(*value);
+            Sample::TimingDuration(key, delta, _) => (key, Input::Timing(duration_as_nanos(*delta))),
+            Sample::Value(key, value) => (key, Input::Value(*value)),
+            Sample::FloatValue(key, value) => (key, Input::FloatValue(*value)),
+            // Deltas describe a change to a gauge, rather than a value in their own right.
+            _ => return,
+        };
+
+        if let Some(series) = self.data.get_mut(key) {
+            self.limiter.touch(key);
+            if self.limiter.admit_series(&series.histograms, labels) {
+                series.update(labels, value, &*self.clock);
+            }
+        } else if let Some(overflow) = self.limiter.overflow_for(key).cloned() {
+            if let Some(series) = self.data.get_mut(&overflow) {
+                if self.limiter.admit_series(&series.histograms, labels) {
+                    series.update(labels, value, &*self.clock);
                 }
-            },
-            _ => {},
+            }
         }
     }
 
     pub fn upkeep(&mut self, at: Instant) {
-        for (_, histogram) in self.data.iter_mut() {
-            histogram.upkeep(at);
+        for series in self.data.values_mut() {
+            for histogram in series.histograms.values_mut() {
+                histogram.upkeep(at);
+            }
         }
     }
 
-    pub fn snapshot(&self, key: T) -> Option<HdrHistogram<u64>> {
-        match self.data.get(&key) {
-            Some(wh) => Some(wh.merged()),
-            _ => None,
+    /// Gets the percentiles configured for the given key, if any were specifically configured.
+    pub fn percentiles(&self, key: &T) -> Option<&[Percentile]> {
+        self.data.get(key).and_then(|series| series.conf.percentiles.as_deref())
+    }
+
+    /// Gets the scale that value samples for the given key are recorded with.
+    pub fn scale(&self, key: &T) -> f64 {
+        self.data.get(key).map_or(1.0, |series| series.conf.scale)
+    }
+
+    /// Gets whether or not summaries for the given key should include the encoded histogram.
+    pub fn encoded(&self, key: &T) -> bool {
+        self.data.get(key).is_some_and(|series| series.conf.encoded)
+    }
+
+    /// Gets the number of registered keys.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Gets the number of labeled series rejected for exceeding the series limit.
+    pub fn rejected_series(&self) -> usize {
+        self.limiter.rejected_series()
+    }
+
+    pub fn snapshots(&self, key: &T) -> Vec<(Labels, HdrHistogram<u64>)> {
+        match self.data.get(key) {
+            Some(series) => series.histograms.iter().map(|(labels, wh)| (labels.clone(), wh.merged())).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A value to be recorded by a histogram.
+#[derive(Clone, Copy)]
+enum Input {
+    Timing(u64),
+    Value(u64),
+    FloatValue(f64),
+}
+
+impl Series {
+    fn update(&mut self, labels: &Labels, input: Input, clock: &dyn Clock) {
+        let scale = self.conf.scale;
+        let value = match input {
+            Input::Timing(value) => value,
+            Input::Value(value) if scale == 1.0 => value,
+            // Casting saturates, so negative values become zero, and huge values the maximum.
+            Input::Value(value) => (value as f64 * scale).round() as u64,
+            Input::FloatValue(value) => (value * scale).round() as u64,
+        };
+
+        if let Some(entry) = self.histograms.get_mut(labels) {
+            entry.update(value);
+            return;
         }
+
+        let mut entry = WindowedHistogram::new(&self.conf, clock.now());
+        entry.update(value);
+        self.histograms.insert(labels.clone(), entry);
     }
 }
 
+/// The most buckets a windowed histogram is split into, however fine its granularity.
+pub(crate) const MAX_BUCKETS: usize = 128;
+
 pub struct WindowedHistogram {
     buckets: Vec<HdrHistogram<u64>>,
     num_buckets: usize,
@@ -73,31 +195,59 @@ pub struct WindowedHistogram {
 }
 
 impl WindowedHistogram {
-    pub fn new(window: Duration, granularity: Duration) -> WindowedHistogram {
-        let num_buckets = ((duration_as_nanos(window) / duration_as_nanos(granularity)) as usize) + 1;
+    pub fn new(conf: &HistogramConfiguration, now: Instant) -> WindowedHistogram {
+        // A granularity too fine for the window is coarsened, rather than allocating a histogram
+        // for every one of its periods.
+        let window = duration_as_nanos(conf.window);
+        let mut granularity = duration_as_nanos(conf.granularity);
+        let max_periods = (MAX_BUCKETS - 1) as u64;
+        if window / granularity > max_periods {
+            granularity = window.div_ceil(max_periods);
+        }
+        let num_buckets = (window / granularity) as usize + 1;
         let mut buckets = Vec::with_capacity(num_buckets);
 
         for _ in 0..num_buckets {
-            let histogram = HdrHistogram::new_with_bounds(1, u64::max_value(), 3).unwrap();
+            let histogram = HdrHistogram::new_with_bounds(conf.lowest, conf.highest, conf.significant_figures)
+                .expect("histogram configuration should be validated");
             buckets.push(histogram);
         }
 
         WindowedHistogram {
-            buckets: buckets,
-            num_buckets: num_buckets,
+            buckets,
+            num_buckets,
             bucket_index: 0,
-            last_upkeep: Instant::now(),
-            granularity: granularity,
+            last_upkeep: now,
+            granularity: Duration::from_nanos(granularity),
         }
     }
 
+    /// Rotates the window forward to the given time.
+    ///
+    /// Advances by one bucket for every whole granularity period elapsed since the last rotation,
+    /// clearing every bucket passed over, so that even after a long gap between calls, only values
+    /// from within the window remain.
     pub fn upkeep(&mut self, at: Instant) {
-        if at >= self.last_upkeep + self.granularity {
+        let elapsed = match at.checked_duration_since(self.last_upkeep) {
+            Some(elapsed) => duration_as_nanos(elapsed),
+            None => return,
+        };
+        let granularity = duration_as_nanos(self.granularity);
+        let periods = elapsed / granularity;
+        if periods == 0 {
+            return;
+        }
+
+        // Past a full rotation, every bucket has been cleared, and there's nothing more to do.
+        let steps = periods.min(self.num_buckets as u64) as usize;
+        for _ in 0..steps {
             self.bucket_index += 1;
             self.bucket_index %= self.num_buckets;
             self.buckets[self.bucket_index].clear();
-            self.last_upkeep = at;
         }
+
+        // Stay aligned to the granularity, rather than drifting with every late call.
+        self.last_upkeep += Duration::from_nanos(periods * granularity);
     }
 
     pub fn update(&mut self, value: u64) {
@@ -117,95 +267,245 @@ impl WindowedHistogram {
 #[cfg(test)]
 mod tests {
     use std::time::{Instant, Duration};
-    use super::{Histogram, WindowedHistogram};
-    use data::Sample;
+    use super::{Histogram, WindowedHistogram, MAX_BUCKETS};
+    use configuration::{CardinalityPolicy, HistogramConfiguration};
+    use data::{Labels, Limiter, Percentile, Registration, Sample};
+
+    fn five_second_window() -> HistogramConfiguration {
+        HistogramConfiguration::new()
+            .window(Duration::new(5, 0))
+            .granularity(Duration::new(1, 0))
+    }
 
     #[test]
     fn test_histogram_unregistered_update() {
-        let mut histogram = Histogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut histogram = Histogram::new(five_second_window());
 
         let key = "foo".to_owned();
         let t0 = Instant::now();
         let t1 = t0 + Duration::from_millis(100);
         let sample = Sample::Timing(key.clone(), t0, t1, 1);
-        histogram.update(&sample);
+        histogram.update(&sample, &Labels::new());
 
-        let value = histogram.snapshot(key);
-        assert!(value.is_none());
+        let values = histogram.snapshots(&key);
+        assert!(values.is_empty());
     }
 
     #[test]
     fn test_histogram_simple_update() {
-        let mut histogram = Histogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut histogram = Histogram::new(five_second_window());
 
         let key = "foo".to_owned();
-        histogram.register(key.clone());
+        histogram.register(key.clone(), None);
 
         let t0 = Instant::now();
         let t1 = t0 + Duration::from_nanos(1245);
         let sample = Sample::Timing(key.clone(), t0, t1, 1);
-        histogram.update(&sample);
+        histogram.update(&sample, &Labels::new());
 
-        let value = histogram.snapshot(key);
-        assert!(value.is_some());
+        let mut values = histogram.snapshots(&key);
+        assert_eq!(values.len(), 1);
 
-        let hdr = value.unwrap();
+        let (labels, hdr) = values.remove(0);
+        assert!(labels.is_empty());
         assert_eq!(hdr.len(), 1);
         assert_eq!(hdr.max(), 1245);
     }
 
     #[test]
     fn test_histogram_sample_support() {
-        let mut histogram = Histogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut histogram = Histogram::new(five_second_window());
 
         // Count samples.
         let ckey = "ckey".to_owned();
-        histogram.register(ckey.clone());
+        histogram.register(ckey.clone(), None);
 
         let csample = Sample::Count(ckey.clone(), 42);
-        histogram.update(&csample);
+        histogram.update(&csample, &Labels::new());
 
-        let cvalue = histogram.snapshot(ckey);
-        assert!(cvalue.is_some());
+        let mut cvalues = histogram.snapshots(&ckey);
+        assert_eq!(cvalues.len(), 1);
 
-        let chdr = cvalue.unwrap();
+        let (_, chdr) = cvalues.remove(0);
         assert_eq!(chdr.len(), 0);
 
         // Timing samples.
         let tkey = "tkey".to_owned();
-        histogram.register(tkey.clone());
+        histogram.register(tkey.clone(), None);
 
         let t0 = Instant::now();
         let t1 = t0 + Duration::from_nanos(1692);
         let tsample = Sample::Timing(tkey.clone(), t0, t1, 73);
-        histogram.update(&tsample);
+        histogram.update(&tsample, &Labels::new());
 
-        let tvalue = histogram.snapshot(tkey);
-        assert!(tvalue.is_some());
+        let mut tvalues = histogram.snapshots(&tkey);
+        assert_eq!(tvalues.len(), 1);
 
-        let thdr = tvalue.unwrap();
+        let (_, thdr) = tvalues.remove(0);
         assert_eq!(thdr.len(), 1);
         assert_eq!(thdr.max(), 1692);
 
         // Value samples.
         let vkey = "vkey".to_owned();
-        histogram.register(vkey.clone());
+        histogram.register(vkey.clone(), None);
 
         let vsample = Sample::Value(vkey.clone(), 22);
-        histogram.update(&vsample);
+        histogram.update(&vsample, &Labels::new());
 
-        let vvalue = histogram.snapshot(vkey);
-        assert!(vvalue.is_some());
+        let mut vvalues = histogram.snapshots(&vkey);
+        assert_eq!(vvalues.len(), 1);
 
-        let vhdr = vvalue.unwrap();
+        let (_, vhdr) = vvalues.remove(0);
         assert_eq!(vhdr.len(), 1);
         assert_eq!(vhdr.max(), 22);
     }
 
+    #[test]
+    fn test_histogram_timing_durations() {
+        let mut histogram = Histogram::new(five_second_window());
+
+        let key = "foo".to_owned();
+        histogram.register(key.clone(), None);
+
+        histogram.update(&Sample::TimingDuration(key.clone(), Duration::from_nanos(1245), 1), &Labels::new());
+
+        // Reversed instants are ignored, rather than panicking.
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_nanos(100);
+        histogram.update(&Sample::Timing(key.clone(), t1, t0, 1), &Labels::new());
+
+        let (_, hdr) = histogram.snapshots(&key).remove(0);
+        assert_eq!(hdr.len(), 1);
+        assert_eq!(hdr.max(), 1245);
+    }
+
+    #[test]
+    fn test_histogram_labeled_update() {
+        let mut histogram = Histogram::new(five_second_window());
+
+        let key = "foo".to_owned();
+        histogram.register(key.clone(), None);
+
+        let labels = Labels::new().with("endpoint", "/a");
+        histogram.update(&Sample::Value(key.clone(), 10), &labels);
+        histogram.update(&Sample::Value(key.clone(), 20), &labels);
+        histogram.update(&Sample::Value(key.clone(), 30), &Labels::new());
+
+        let mut values = histogram.snapshots(&key);
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values.len(), 2);
+        assert!(values[0].0.is_empty());
+        assert_eq!(values[0].1.len(), 1);
+        assert_eq!(values[1].0, labels);
+        assert_eq!(values[1].1.len(), 2);
+        assert_eq!(values[1].1.max(), 20);
+    }
+
+    #[test]
+    fn test_histogram_per_key_configuration() {
+        let mut histogram = Histogram::new(five_second_window());
+
+        // Only two significant figures, and a ceiling of 10,000.
+        let low_precision = HistogramConfiguration::new()
+            .significant_figures(2)
+            .bounds(1, 10_000);
+
+        let dkey = "default".to_owned();
+        let lkey = "low".to_owned();
+        histogram.register(dkey.clone(), None);
+        histogram.register(lkey.clone(), Some(low_precision));
+
+        histogram.update(&Sample::Value(dkey.clone(), 12_345), &Labels::new());
+        histogram.update(&Sample::Value(lkey.clone(), 12_345), &Labels::new());
+        histogram.update(&Sample::Value(lkey.clone(), 1_234), &Labels::new());
+
+        let (_, dhdr) = histogram.snapshots(&dkey).remove(0);
+        assert_eq!(dhdr.sigfig(), 3);
+        assert_eq!(dhdr.high(), u64::MAX);
+        assert!(dhdr.max() >= 12_345);
+
+        let (_, lhdr) = histogram.snapshots(&lkey).remove(0);
+        assert_eq!(lhdr.sigfig(), 2);
+        assert_eq!(lhdr.high(), 10_000);
+        assert_eq!(lhdr.len(), 2);
+    }
+
+    #[test]
+    fn test_histogram_per_key_percentiles() {
+        let mut histogram = Histogram::new(five_second_window());
+
+        let percentiles = vec![Percentile("p95".to_owned(), 95.0), Percentile("p9999".to_owned(), 99.99)];
+        let conf = five_second_window().percentiles(percentiles.clone());
+
+        let dkey = "default".to_owned();
+        let pkey = "slo".to_owned();
+        histogram.register(dkey.clone(), None);
+        histogram.register(pkey.clone(), Some(conf));
+
+        assert!(histogram.percentiles(&dkey).is_none());
+        assert_eq!(histogram.percentiles(&pkey), Some(percentiles.as_slice()));
+        assert!(histogram.percentiles(&"unknown".to_owned()).is_none());
+    }
+
+    #[test]
+    fn test_windowed_histogram_long_gaps() {
+        let now = Instant::now();
+        let mut wh = WindowedHistogram::new(&five_second_window(), now);
+
+        // Calls within a period, however many, don't rotate anything.
+        wh.update(1);
+        for millis in 1..1000 {
+            wh.upkeep(now + Duration::from_millis(millis));
+        }
+        wh.update(2);
+        assert_eq!(wh.merged().len(), 2);
+        assert_eq!(wh.bucket_index, 0);
+
+        // A late call rotates past every elapsed period at once.
+        let now = now + Duration::from_millis(2_500);
+        wh.upkeep(now);
+        assert_eq!(wh.bucket_index, 2);
+        wh.update(3);
+
+        // The next rotation stays aligned to the original periods: 3s, not 3.5s.
+        wh.upkeep(now + Duration::from_millis(500));
+        assert_eq!(wh.bucket_index, 3);
+        assert_eq!(wh.merged().len(), 3);
+
+        // After a long stall, nothing from before it remains.
+        let now = now + Duration::from_secs(30);
+        wh.upkeep(now);
+        assert_eq!(wh.merged().len(), 0);
+
+        wh.update(4);
+        wh.upkeep(now + Duration::from_secs(5));
+        assert_eq!(wh.merged().len(), 1);
+        wh.upkeep(now + Duration::from_secs(6));
+        assert_eq!(wh.merged().len(), 0);
+    }
+
+    #[test]
+    fn test_windowed_histogram_bucket_limit() {
+        let now = Instant::now();
+        let conf = five_second_window().granularity(Duration::from_nanos(1));
+        let mut wh = WindowedHistogram::new(&conf, now);
+
+        // Rather than a bucket per nanosecond, the granularity is coarsened to fit the limit.
+        assert!(wh.num_buckets <= MAX_BUCKETS);
+        assert!(wh.granularity >= Duration::from_secs(5) / (MAX_BUCKETS as u32 - 1));
+
+        // The window still covers the configured duration.
+        wh.update(1);
+        wh.upkeep(now + Duration::from_millis(4_900));
+        assert_eq!(wh.merged().len(), 1);
+        wh.upkeep(now + Duration::from_millis(5_100));
+        assert_eq!(wh.merged().len(), 0);
+    }
+
     #[test]
     fn test_windowed_histogram_rollover() {
-        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0));
         let now = Instant::now();
+        let mut wh = WindowedHistogram::new(&five_second_window(), now);
 
         let merged = wh.merged();
         assert_eq!(merged.len(), 0);
@@ -260,4 +560,49 @@ mod tests {
         let merged = wh.merged();
         assert_eq!(merged.len(), 3);
     }
+
+    #[test]
+    fn test_histogram_scaled_values() {
+        let mut histogram = Histogram::new(five_second_window());
+
+        let (fkey, skey) = ("float".to_owned(), "scaled".to_owned());
+        histogram.register(fkey.clone(), None);
+        histogram.register(skey.clone(), Some(five_second_window().scale(100.0)));
+        assert_eq!(histogram.scale(&fkey), 1.0);
+        assert_eq!(histogram.scale(&skey), 100.0);
+
+        // Without a scale, floating-point values are rounded, and negative values become zero.
+        histogram.update(&Sample::FloatValue(fkey.clone(), 2.6), &Labels::new());
+        histogram.update(&Sample::FloatValue(fkey.clone(), -4.0), &Labels::new());
+        let (_, fhdr) = histogram.snapshots(&fkey).remove(0);
+        assert_eq!((fhdr.len(), fhdr.min(), fhdr.max()), (2, 0, 3));
+
+        // With a scale, both floating-point and integer values are scaled, but timings are not.
+        let t0 = Instant::now();
+        histogram.update(&Sample::FloatValue(skey.clone(), 0.25), &Labels::new());
+        histogram.update(&Sample::Value(skey.clone(), 3), &Labels::new());
+        histogram.update(&Sample::Timing(skey.clone(), t0, t0 + Duration::from_nanos(7), 1), &Labels::new());
+        let (_, shdr) = histogram.snapshots(&skey).remove(0);
+        assert_eq!(shdr.len(), 3);
+        assert_eq!(shdr.min(), 7);
+        assert_eq!(shdr.value_at_percentile(50.0), 25);
+        assert_eq!(shdr.max(), 300);
+    }
+
+    #[test]
+    fn test_histogram_reject() {
+        let limiter = Limiter::new(Some(1), CardinalityPolicy::Reject);
+        let mut histogram = Histogram::new(HistogramConfiguration::new()).with_limiter(limiter);
+
+        let (foo, bar) = ("foo".to_owned(), "bar".to_owned());
+        assert_eq!(histogram.register(foo.clone(), None), Registration::Registered);
+        assert_eq!(histogram.register(bar.clone(), None), Registration::Rejected);
+        // Registering an existing key again doesn't count against the limit.
+        assert_eq!(histogram.register(foo.clone(), None), Registration::Registered);
+
+        histogram.update(&Sample::Value(foo.clone(), 42), &Labels::new());
+        histogram.update(&Sample::Value(bar.clone(), 42), &Labels::new());
+        assert_eq!(histogram.snapshots(&foo)[0].1.len(), 1);
+        assert!(histogram.snapshots(&bar).is_empty());
+    }
 }
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A set of labels attached to a metric.
+///
+/// Labels are key/value pairs that add dimensions to a metric: a single logical metric, such as
+/// request latency, can be split into many series by endpoint, status code, and so on.
+///
+/// Labels are kept sorted by name, so the same set of labels always compares and hashes the same
+/// regardless of the order they were given in.  An empty label set does not allocate, and cloning
+/// a non-empty label set is cheap.
+///
+/// With the `serde` feature enabled, labels are serialized as a map of label names to values.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Labels(Option<Arc<Vec<(String, String)>>>);
+
+impl Labels {
+    /// Creates an empty label set.
+    pub fn new() -> Labels {
+        Labels(None)
+    }
+
+    /// Adds a label to this label set.
+    ///
+    /// If a label with the same name already exists, its value is replaced.
+    pub fn with<K: Into<String>, V: Into<String>>(self, name: K, value: V) -> Labels {
+        let mut labels = match self.0 {
+            Some(labels) => Arc::try_unwrap(labels).unwrap_or_else(|labels| (*labels).clone()),
+            None => Vec::new(),
+        };
+
+        let name = name.into();
+        let value = value.into();
+        match labels.binary_search_by(|(n, _)| n.as_str().cmp(&name)) {
+            Ok(i) => labels[i].1 = value,
+            Err(i) => labels.insert(i, (name, value)),
+        }
+
+        Labels(Some(Arc::new(labels)))
+    }
+
+    /// Whether or not this label set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Gets the number of labels in this label set.
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map(|labels| labels.len()).unwrap_or(0)
+    }
+
+    /// Gets the value of the label with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    /// Iterates over all labels, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .flat_map(|labels| labels.iter())
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> From<Vec<(K, V)>> for Labels {
+    fn from(labels: Vec<(K, V)>) -> Labels {
+        labels.into_iter().fold(Labels::new(), |acc, (n, v)| acc.with(n, v))
+    }
+}
+
+/// Renders labels as `{name="value",...}`, or as nothing at all when empty.
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, "{{")?;
+        for (i, (name, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}=\"", name)?;
+            for c in value.chars() {
+                match c {
+                    '\\' => write!(f, "\\\\")?,
+                    '"' => write!(f, "\\\"")?,
+                    '\n' => write!(f, "\\n")?,
+                    c => write!(f, "{}", c)?,
+                }
+            }
+            write!(f, "\"")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// A metric key, along with its labels.
+///
+/// This is how a single series of a metric is identified within a `Snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Key<T> {
+    name: T,
+    labels: Labels,
+}
+
+impl<T> Key<T> {
+    /// Creates a new `Key` from the given metric key and labels.
+    pub fn new(name: T, labels: Labels) -> Key<T> {
+        Key { name, labels }
+    }
+
+    /// Gets the metric key.
+    pub fn name(&self) -> &T {
+        &self.name
+    }
+
+    /// Gets the labels.
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+}
+
+impl<T> From<T> for Key<T> {
+    fn from(name: T) -> Key<T> {
+        Key::new(name, Labels::new())
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Key<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Key, Labels};
+
+    #[test]
+    fn test_labels_sorted_and_deduplicated() {
+        let a = Labels::new().with("status", "200").with("endpoint", "/a");
+        let b = Labels::from(vec![("endpoint", "/b"), ("status", "200"), ("endpoint", "/a")]);
+
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 2);
+        assert_eq!(a.get("endpoint"), Some("/a"));
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![("endpoint", "/a"), ("status", "200")]);
+    }
+
+    #[test]
+    fn test_labels_empty() {
+        let labels = Labels::new();
+        assert!(labels.is_empty());
+        assert_eq!(labels.len(), 0);
+        assert_eq!(labels, Labels::from(Vec::<(String, String)>::new()));
+        assert_eq!(labels.to_string(), "");
+    }
+
+    #[test]
+    fn test_key_display() {
+        let key = Key::new("requests", Labels::new().with("path", "/\"q\"").with("code", "200"));
+        assert_eq!(key.to_string(), "requests{code=\"200\",path=\"/\\\"q\\\"\"}");
+        assert_eq!(Key::from("requests").to_string(), "requests");
+    }
+}
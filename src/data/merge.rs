@@ -0,0 +1,174 @@
+use std::io::Cursor;
+use hdrhistogram::Histogram as HdrHistogram;
+use hdrhistogram::serialization::Deserializer;
+use error::Error;
+use super::{Percentile, Summary};
+
+/// A histogram merged from encoded histograms, such as those from the snapshots of many hosts.
+///
+/// Percentiles can't be averaged, but histograms can be merged: with
+/// `HistogramConfiguration::encoded` enabled, every `Summary` holds its histogram in the standard
+/// HdrHistogram V2 compressed format, and merging those gives percentiles across all of them.
+///
+/// The merged histogram resizes itself as needed, so histograms with different bounds can be
+/// merged, but histograms of scaled values can only be merged with those of the same scale.
+pub struct MergedHistogram {
+    histogram: Option<HdrHistogram<u64>>,
+    scale: Option<f64>,
+}
+
+impl Default for MergedHistogram {
+    fn default() -> MergedHistogram {
+        MergedHistogram::new()
+    }
+}
+
+impl MergedHistogram {
+    /// Creates an empty `MergedHistogram`.
+    pub fn new() -> MergedHistogram {
+        MergedHistogram {
+            histogram: None,
+            scale: None,
+        }
+    }
+
+    /// Decodes a histogram, in the HdrHistogram V2 format, and merges it.
+    ///
+    /// The histogram is assumed to hold unscaled values: use `merge_summary` for summaries of
+    /// scaled values.
+    pub fn merge(&mut self, encoded: &[u8]) -> Result<(), Error> {
+        self.merge_scaled(encoded, 1.0)
+    }
+
+    /// Merges the encoded histogram held by the given summary.
+    ///
+    /// Returns `Error::InvalidHistogram` if the summary holds no encoded histogram, and
+    /// `Error::IncompatibleHistogram` if its scale differs from that of the histograms merged so
+    /// far.
+    pub fn merge_summary(&mut self, summary: &Summary) -> Result<(), Error> {
+        match summary.encoded() {
+            Some(encoded) => self.merge_scaled(encoded, summary.scale()),
+            None => Err(Error::InvalidHistogram),
+        }
+    }
+
+    fn merge_scaled(&mut self, encoded: &[u8], scale: f64) -> Result<(), Error> {
+        if self.scale.is_some_and(|existing| existing != scale) {
+            return Err(Error::IncompatibleHistogram);
+        }
+
+        let decoded: HdrHistogram<u64> = Deserializer::new()
+            .deserialize(&mut Cursor::new(encoded))
+            .map_err(|_| Error::InvalidHistogram)?;
+
+        match self.histogram {
+            Some(ref mut histogram) => histogram.add(&decoded).map_err(|_| Error::IncompatibleHistogram)?,
+            None => {
+                let mut histogram = decoded;
+                histogram.auto(true);
+                self.histogram = Some(histogram);
+            },
+        }
+        self.scale = Some(scale);
+        Ok(())
+    }
+
+    /// Gets the number of values recorded across every merged histogram.
+    pub fn len(&self) -> u64 {
+        self.histogram.as_ref().map_or(0, |h| h.len())
+    }
+
+    /// Whether or not any values have been merged.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the merged histogram itself, if anything has been merged.
+    pub fn histogram(&self) -> Option<&HdrHistogram<u64>> {
+        self.histogram.as_ref()
+    }
+
+    /// Extracts the given percentiles from the merged histogram.
+    ///
+    /// The summary carries the scale of the merged histograms, and holds the merged histogram,
+    /// encoded, so that it can itself be merged further.  Returns `None` if nothing has been
+    /// merged.
+    pub fn summary(&self, percentiles: &[Percentile]) -> Option<Summary> {
+        self.histogram.as_ref().map(|h| {
+            Summary::from_histogram(h, percentiles)
+                .with_scale(self.scale.unwrap_or(1.0))
+                .with_encoded(h)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergedHistogram;
+    use data::{Percentile, Summary};
+    use error::Error;
+    use hdrhistogram::Histogram as HdrHistogram;
+
+    fn histogram(lowest: u64, highest: u64, values: &[u64]) -> HdrHistogram<u64> {
+        let mut h = HdrHistogram::new_with_bounds(lowest, highest, 3).unwrap();
+        for value in values {
+            h.saturating_record(*value);
+        }
+        h
+    }
+
+    #[test]
+    fn test_merged_histogram() {
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0), Percentile("max".to_owned(), 100.0)];
+
+        // Two hosts, with different bounds: the second's values don't fit in the first's.
+        let (ha, hb) = (histogram(1, 100, &[50, 50, 50]), histogram(1, u64::MAX, &[1_000]));
+        let a = Summary::from_histogram(&ha, &percentiles).with_encoded(&ha);
+        let b = Summary::from_histogram(&hb, &percentiles).with_encoded(&hb);
+
+        let mut merged = MergedHistogram::new();
+        assert!(merged.is_empty());
+        assert!(merged.summary(&percentiles).is_none());
+
+        merged.merge_summary(&a).unwrap();
+        merged.merge(b.encoded().unwrap()).unwrap();
+        assert_eq!(merged.len(), 4);
+
+        let summary = merged.summary(&percentiles).unwrap();
+        assert_eq!(summary.count(), 4);
+        assert_eq!(summary.percentile("p50"), Some(&50));
+        assert_eq!(summary.percentile("max"), Some(&1_000));
+
+        // The merged summary can be merged again.
+        let mut again = MergedHistogram::new();
+        again.merge_summary(&summary).unwrap();
+        assert_eq!(again.len(), 4);
+    }
+
+    #[test]
+    fn test_merged_histogram_errors() {
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+        let h = histogram(1, u64::MAX, &[250]);
+
+        let mut merged = MergedHistogram::new();
+        match merged.merge(&[1, 2, 3]) {
+            Err(Error::InvalidHistogram) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        match merged.merge_summary(&Summary::from_histogram(&h, &percentiles)) {
+            Err(Error::InvalidHistogram) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let scaled = Summary::from_histogram(&h, &percentiles).with_scale(1000.0).with_encoded(&h);
+        merged.merge_summary(&scaled).unwrap();
+        match merged.merge_summary(&Summary::from_histogram(&h, &percentiles).with_encoded(&h)) {
+            Err(Error::IncompatibleHistogram) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let summary = merged.summary(&percentiles).unwrap();
+        assert_eq!(summary.scaled_percentile("p50"), Some(0.25));
+    }
+}
@@ -1,17 +1,23 @@
-use std::marker::PhantomData;
-use std::time::Instant;
-use fnv::FnvHashMap;
-use std::hash::Hash;
-use std::fmt::Display;
-use hdrhistogram::Histogram as HdrHistogram;
+use std::time::{Duration, Instant};
 
+mod cardinality;
 pub mod counter;
 pub mod gauge;
 pub mod histogram;
+pub mod key;
+pub mod merge;
+pub mod snapshot;
+#[cfg(feature = "serde")]
+mod serialization;
 
+pub(crate) use self::cardinality::{Limiter, Registration};
 pub(crate) use self::counter::Counter;
-pub(crate) use self::gauge::Gauge;
+pub(crate) use self::gauge::{Gauge, Level};
 pub(crate) use self::histogram::Histogram;
+pub use self::key::{Key, Labels};
+pub use self::merge::MergedHistogram;
+pub use self::snapshot::{Delta, Snapshot, Summary, TypedMeasurement};
+pub(crate) use self::snapshot::Unscaled;
 
 /// Type of computation against aggregated/processed samples.
 ///
@@ -22,7 +28,11 @@ pub(crate) use self::histogram::Histogram;
 /// histograms, etc.  We treat them a little different because callers are never directly saying
 /// that they want to change the value of a counter, or histogram, they're saying that for a given
 /// metric type, they care about certain facets.
+///
+/// Facets are registered against a metric key, and apply to every set of labels that samples for
+/// that key are sent with: each distinct label set is tracked as its own series.
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Facet<T> {
     /// A count.
     ///
@@ -37,19 +47,64 @@ pub enum Facet<T> {
 
     /// Timing-specific percentiles.
     ///
-    /// The histograms that back percentiles are currently hard-coded to track a windowed view of
-    /// 60 seconds, with a 1 second interval.  That is, they only store the last 60 seconds worth
-    /// of data that they've been given.
+    /// The histograms that back percentiles track a windowed view of the data they've been given:
+    /// by default, the last 10 seconds, with a 1 second granularity.  This can be changed for all
+    /// metrics via `Configuration`, or for a specific metric by registering the facet with a
+    /// `HistogramConfiguration`.
     TimingPercentile(T),
 
     /// Value-specific percentiles.
     ///
-    /// The histograms that back percentiles are currently hard-coded to track a windowed view of
-    /// 60 seconds, with a 1 second interval.  That is, they only store the last 60 seconds worth
-    /// of data that they've been given.
+    /// The histograms that back percentiles track a windowed view of the data they've been given:
+    /// by default, the last 10 seconds, with a 1 second granularity.  This can be changed for all
+    /// metrics via `Configuration`, or for a specific metric by registering the facet with a
+    /// `HistogramConfiguration`.
     ValuePercentile(T),
 }
 
+impl<T> Facet<T> {
+    /// Gets the metric key this facet is registered against.
+    pub(crate) fn key(&self) -> &T {
+        match *self {
+            Facet::Count(ref key) => key,
+            Facet::Gauge(ref key) => key,
+            Facet::TimingPercentile(ref key) => key,
+            Facet::ValuePercentile(ref key) => key,
+        }
+    }
+}
+
+/// The kind of a facet, without a metric key.
+///
+/// Used to describe which facets should be registered for a metric key that isn't known yet, such
+/// as when auto-registering facets via `AutoRegistration`.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum FacetKind {
+    /// A count.  See `Facet::Count`.
+    Count,
+
+    /// A gauge.  See `Facet::Gauge`.
+    Gauge,
+
+    /// Timing-specific percentiles.  See `Facet::TimingPercentile`.
+    TimingPercentile,
+
+    /// Value-specific percentiles.  See `Facet::ValuePercentile`.
+    ValuePercentile,
+}
+
+impl FacetKind {
+    /// Creates a facet of this kind for the given metric key.
+    pub fn facet<T>(self, key: T) -> Facet<T> {
+        match self {
+            FacetKind::Count => Facet::Count(key),
+            FacetKind::Gauge => Facet::Gauge(key),
+            FacetKind::TimingPercentile => Facet::TimingPercentile(key),
+            FacetKind::ValuePercentile => Facet::ValuePercentile(key),
+        }
+    }
+}
+
 /// A measurement.
 ///
 /// Samples are the decoupled way of submitting data into the sink.  Likewise with facets, metric
@@ -63,6 +118,10 @@ pub enum Facet<T> {
 ///
 /// There are multiple sample types to support the different types of measurements, which each have
 /// their own specific data they must carry.
+///
+/// With the `serde` feature enabled, samples can be serialized.  As instants have no meaning outside
+/// of the process they were taken in, timing samples are serialized with their duration, in
+/// nanoseconds, and are deserialized as ending at the time of deserialization.
 #[derive(Debug)]
 pub enum Sample<T>
 {
@@ -72,8 +131,19 @@ pub enum Sample<T>
     ///
     /// The count field can represent amounts integral to the event,
     /// such as the number of bytes processed in the given time delta.
+    ///
+    /// Samples where the end comes before the start are ignored, and
+    /// counted by the `hotmic_invalid_samples` internal metric.
     Timing(T, Instant, Instant, u64),
 
+    /// A timed sample, from a precomputed duration.
+    ///
+    /// Like `Timing`, but for durations measured elsewhere, such as
+    /// those reported by a database driver, where there are no
+    /// instants to hand.  A value in nanoseconds can be sent via
+    /// `Duration::from_nanos`.
+    TimingDuration(T, Duration, u64),
+
     /// A counter delta.
     ///
     /// The value is added directly to the existing counter, and so
@@ -85,193 +155,86 @@ pub enum Sample<T>
     ///
     /// Values operate in last-write-wins mode.
     ///
-    /// Values themselves cannot be incremented or decremented, so you
-    /// must hold them externally before sending them.
+    /// Values sent this way replace the current value outright: to
+    /// adjust the current value instead, use a value delta.
+    ///
+    /// Gauges are signed, so values above `i64::MAX` are clamped to it,
+    /// and counted by the `hotmic_clamped_samples` internal metric.
+    /// Histograms record the value as sent.
     Value(T, u64),
-}
-
-/// A labeled percentile.
-///
-/// This represents a floating-point value from 0 to 100, with a string label to be used for
-/// displaying the given percentile.
-#[derive(Clone)]
-pub struct Percentile(pub String, pub f64);
 
-/// A default set of percentiles that should support most use cases.
-///
-/// Contains min (or 0.0), p50 (50.0), p90 (090.0), p99 (99.0), p999 (99.9) and max (100.0).
-pub fn default_percentiles() -> Vec<Percentile> {
-    let mut p = Vec::new();
-    p.push(Percentile("min".to_owned(), 0.0));
-    p.push(Percentile("p50".to_owned(), 50.0));
-    p.push(Percentile("p90".to_owned(), 90.0));
-    p.push(Percentile("p99".to_owned(), 99.0));
-    p.push(Percentile("p999".to_owned(), 99.9));
-    p.push(Percentile("max".to_owned(), 100.0));
-    p
-}
+    /// A value delta, adjusting a gauge.
+    ///
+    /// The delta is added directly to the existing value, and so
+    /// negative deltas will decrease the value, and positive deltas
+    /// will increase the value.  Gauges are signed, so a value can go
+    /// below zero.
+    ///
+    /// This allows many sinks, on many threads, to each adjust a shared
+    /// value -- such as the number of in-flight requests -- without
+    /// having to coordinate.
+    ValueDelta(T, i64),
 
-/// A point-in-time view of metric data.
-pub struct Snapshot<T> {
-    marker: PhantomData<T>,
-    pub signed_data: FnvHashMap<String, i64>,
-    pub unsigned_data: FnvHashMap<String, u64>,
+    /// A single floating-point value, also known as a gauge.
+    ///
+    /// Like `Value`, this replaces the current value outright, and allows
+    /// for gauges holding ratios, load averages or negative values.
+    /// Once a gauge holds a floating-point value, value deltas adjust it
+    /// as a floating-point value, too.
+    ///
+    /// When recorded by a histogram, the value is multiplied by the
+    /// configured scale, and rounded: see `HistogramConfiguration::scale`.
+    FloatValue(T, f64),
 }
 
-impl<T: Send + Eq + Hash + Send + Display + Clone> Snapshot<T> {
-    /// Creates an empty `Snapshot`.
-    pub fn new() -> Snapshot<T> {
-        Snapshot {
-            marker: PhantomData,
-            signed_data: FnvHashMap::default(),
-            unsigned_data: FnvHashMap::default(),
+impl<T> Sample<T> {
+    /// Gets the metric key of this sample.
+    pub(crate) fn key(&self) -> &T {
+        match *self {
+            Sample::Timing(ref key, ..) => key,
+            Sample::TimingDuration(ref key, ..) => key,
+            Sample::Count(ref key, _) => key,
+            Sample::Value(ref key, _) => key,
+            Sample::ValueDelta(ref key, _) => key,
+            Sample::FloatValue(ref key, _) => key,
         }
     }
 
-    /// Stores a counter value for the given metric key.
-    pub fn set_count(&mut self, key: T, value: i64) {
-        let fkey = format!("{}_count", key);
-        self.signed_data.insert(fkey, value);
-    }
-
-    /// Stores a gauge value for the given metric key.
-    pub fn set_value(&mut self, key: T, value: u64) {
-        let fkey = format!("{}_value", key);
-        self.unsigned_data.insert(fkey, value);
-    }
-
-    /// Sets timing percentiles for the given metric key.
-    ///
-    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
-    pub fn set_timing_percentiles(&mut self, key: T, h: HdrHistogram<u64>, percentiles: &[Percentile]) {
-        for percentile in percentiles {
-            let fkey = format!("{}_ns_{}", key, percentile.0);
-            let value = h.value_at_percentile(percentile.1);
-            self.unsigned_data.insert(fkey, value);
+    /// Whether or not this sample can be processed: timings must not end before they start.
+    pub(crate) fn is_valid(&self) -> bool {
+        match *self {
+            Sample::Timing(_, start, end, _) => end >= start,
+            _ => true,
         }
     }
 
-    /// Sets value percentiles for the given metric key.
-    ///
-    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
-    pub fn set_value_percentiles(&mut self, key: T, h: HdrHistogram<u64>, percentiles: &[Percentile]) {
-        for percentile in percentiles {
-            let fkey = format!("{}_value_{}", key, percentile.0);
-            let value = h.value_at_percentile(percentile.1);
-            self.unsigned_data.insert(fkey, value);
+    /// Whether or not this sample holds a value too large for a gauge, which is clamped.
+    pub(crate) fn is_clamped(&self) -> bool {
+        match *self {
+            Sample::Value(_, value) => value > i64::MAX as u64,
+            _ => false,
         }
     }
-
-    /// Gets the counter value for the given metric key.
-    ///
-    /// Returns `None` if the metric key has no counter value in this snapshot.
-    pub fn count(&self, key: &T) -> Option<&i64> {
-        let fkey = format!("{}_count", key);
-        self.signed_data.get(&fkey)
-    }
-
-    /// Gets the gauge value for the given metric key.
-    ///
-    /// Returns `None` if the metric key has no gauge value in this snapshot.
-    pub fn value(&self, key: &T) -> Option<&u64> {
-        let fkey = format!("{}_value", key);
-        self.unsigned_data.get(&fkey)
-    }
-
-    /// Gets the given timing percentile for given metric key.
-    ///
-    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
-    pub fn timing_percentile(&self, key: &T, percentile: Percentile) -> Option<&u64> {
-        let fkey = format!("{}_ns_{}", key, percentile.0);
-        self.unsigned_data.get(&fkey)
-    }
-
-    /// Gets the given value percentile for the given metric key.
-    ///
-    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
-    pub fn value_percentile(&self, key: &T, percentile: Percentile) -> Option<&u64> {
-        let fkey = format!("{}_value_{}", key, percentile.0);
-        self.unsigned_data.get(&fkey)
-    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{Snapshot, Percentile};
-    use hdrhistogram::Histogram;
-
-    #[test]
-    fn test_snapshot_simple_set_and_get() {
-        let key = "ok".to_owned();
-        let mut snapshot = Snapshot::new();
-        snapshot.set_count(key.clone(), 1);
-        snapshot.set_value(key.clone(), 42);
-
-        assert_eq!(snapshot.count(&key).unwrap(), &1);
-        assert_eq!(snapshot.value(&key).unwrap(), &42);
-    }
-
-    #[test]
-    fn test_snapshot_percentiles() {
-        let mut snapshot = Snapshot::new();
-
-        {
-            let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
-            h1.saturating_record(500_000);
-            h1.saturating_record(750_000);
-            h1.saturating_record(1_000_000);
-            h1.saturating_record(1_250_000);
-
-            let tkey = "ok".to_owned();
-            let mut tpercentiles = Vec::new();
-            tpercentiles.push(Percentile("min".to_owned(), 0.0));
-            tpercentiles.push(Percentile("p50".to_owned(), 50.0));
-            tpercentiles.push(Percentile("p99".to_owned(), 99.0));
-            tpercentiles.push(Percentile("max".to_owned(), 100.0));
-
-            snapshot.set_timing_percentiles(tkey.clone(), h1, &tpercentiles);
-
-            let min_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[0].clone());
-            let p50_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[1].clone());
-            let p99_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[2].clone());
-            let max_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[3].clone());
-            let fake_tpercentile = snapshot.timing_percentile(&tkey, Percentile("fake".to_owned(), 63.0));
-
-            assert!(min_tpercentile.is_some());
-            assert!(p50_tpercentile.is_some());
-            assert!(p99_tpercentile.is_some());
-            assert!(max_tpercentile.is_some());
-            assert!(fake_tpercentile.is_none());
-        }
-
-        {
-            let mut h2 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
-            h2.saturating_record(500_000);
-            h2.saturating_record(750_000);
-            h2.saturating_record(1_000_000);
-            h2.saturating_record(1_250_000);
-
-            let vkey = "ok".to_owned();
-            let mut vpercentiles = Vec::new();
-            vpercentiles.push(Percentile("min".to_owned(), 0.0));
-            vpercentiles.push(Percentile("p50".to_owned(), 50.0));
-            vpercentiles.push(Percentile("p99".to_owned(), 99.0));
-            vpercentiles.push(Percentile("max".to_owned(), 100.0));
-
-            snapshot.set_value_percentiles(vkey.clone(), h2, &vpercentiles);
-
-            let min_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[0].clone());
-            let p50_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[1].clone());
-            let p99_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[2].clone());
-            let max_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[3].clone());
-            let fake_vpercentile = snapshot.value_percentile(&vkey, Percentile("fake".to_owned(), 63.0));
-
-            assert!(min_vpercentile.is_some());
-            assert!(p50_vpercentile.is_some());
-            assert!(p99_vpercentile.is_some());
-            assert!(max_vpercentile.is_some());
-            assert!(fake_vpercentile.is_none());
+/// A labeled percentile.
+///
+/// This represents a floating-point value from 0 to 100, with a string label to be used for
+/// displaying the given percentile.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Percentile(pub String, pub f64);
 
-        }
-    }
+/// A default set of percentiles that should support most use cases.
+///
+/// Contains min (or 0.0), p50 (50.0), p90 (090.0), p99 (99.0), p999 (99.9) and max (100.0).
+pub fn default_percentiles() -> Vec<Percentile> {
+    vec![
+        Percentile("min".to_owned(), 0.0),
+        Percentile("p50".to_owned(), 50.0),
+        Percentile("p90".to_owned(), 90.0),
+        Percentile("p99".to_owned(), 99.0),
+        Percentile("p999".to_owned(), 99.9),
+        Percentile("max".to_owned(), 100.0),
+    ]
 }
@@ -0,0 +1,223 @@
+//! Serialization support for the types that can't simply derive it.
+use std::fmt::{self, Display};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use helper::duration_as_nanos;
+use super::{Delta, Labels, Sample, Snapshot, TypedMeasurement};
+
+impl Serialize for Labels {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (name, value) in self.iter() {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Labels {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Labels, D::Error> {
+        struct LabelsVisitor;
+
+        impl<'de> Visitor<'de> for LabelsVisitor {
+            type Value = Labels;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of label names to values")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Labels, M::Error> {
+                let mut labels = Labels::new();
+                while let Some((name, value)) = access.next_entry::<String, String>()? {
+                    labels = labels.with(name, value);
+                }
+                Ok(labels)
+            }
+        }
+
+        deserializer.deserialize_map(LabelsVisitor)
+    }
+}
+
+/// How a `Sample` is represented when serialized: the same, but with timings as a duration.
+#[derive(Serialize)]
+enum SampleRef<'a, T: 'a> {
+    Timing(&'a T, u64, u64),
+    TimingDuration(&'a T, u64, u64),
+    Count(&'a T, i64),
+    Value(&'a T, u64),
+    ValueDelta(&'a T, i64),
+    FloatValue(&'a T, f64),
+}
+
+/// The owned counterpart of `SampleRef`, for deserializing.
+#[derive(Deserialize)]
+enum SampleRepr<T> {
+    Timing(T, u64, u64),
+    TimingDuration(T, u64, u64),
+    Count(T, i64),
+    Value(T, u64),
+    ValueDelta(T, i64),
+    FloatValue(T, f64),
+}
+
+impl<T: Serialize> Serialize for Sample<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match *self {
+            Sample::Timing(ref key, start, end, count) => {
+                let delta = end.checked_duration_since(start).unwrap_or_else(|| Duration::new(0, 0));
+                SampleRef::Timing(key, duration_as_nanos(delta), count)
+            },
+            Sample::TimingDuration(ref key, delta, count) => SampleRef::TimingDuration(key, duration_as_nanos(delta), count),
+            Sample::Count(ref key, value) => SampleRef::Count(key, value),
+            Sample::Value(ref key, value) => SampleRef::Value(key, value),
+            Sample::ValueDelta(ref key, delta) => SampleRef::ValueDelta(key, delta),
+            Sample::FloatValue(ref key, value) => SampleRef::FloatValue(key, value),
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Sample<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Sample<T>, D::Error> {
+        let sample = match SampleRepr::deserialize(deserializer)? {
+            SampleRepr::Timing(key, nanos, count) => {
+                let end = Instant::now();
+                let start = end.checked_sub(Duration::from_nanos(nanos)).unwrap_or(end);
+                Sample::Timing(key, start, end, count)
+            },
+            SampleRepr::TimingDuration(key, nanos, count) => Sample::TimingDuration(key, Duration::from_nanos(nanos), count),
+            SampleRepr::Count(key, value) => Sample::Count(key, value),
+            SampleRepr::Value(key, value) => Sample::Value(key, value),
+            SampleRepr::ValueDelta(key, delta) => Sample::ValueDelta(key, delta),
+            SampleRepr::FloatValue(key, value) => Sample::FloatValue(key, value),
+        };
+        Ok(sample)
+    }
+}
+
+/// How a `Snapshot` is represented when serialized: its measurements, without the flattened view
+/// or the index, which are rebuilt when deserializing.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+struct SnapshotRepr<T> {
+    measurements: Vec<TypedMeasurement<T>>,
+    internal: Vec<TypedMeasurement<String>>,
+    #[serde(default)]
+    deltas: Vec<Delta<T>>,
+    #[serde(default)]
+    interval: Option<Duration>,
+}
+
+impl<'de, T> Deserialize<'de> for Snapshot<T>
+    where T: Deserialize<'de> + Send + Eq + Hash + Display + Clone
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Snapshot<T>, D::Error> {
+        let repr = SnapshotRepr::deserialize(deserializer)?;
+        Ok(Snapshot::from_parts(repr.measurements, repr.internal, repr.deltas, repr.interval))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{Facet, Key, Labels, Percentile, Sample, Snapshot};
+    use hdrhistogram::Histogram;
+    use serde_json;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_labels_roundtrip() {
+        let labels = Labels::new().with("status", "200").with("endpoint", "/a");
+
+        let json = serde_json::to_string(&labels).unwrap();
+        assert_eq!(json, r#"{"endpoint":"/a","status":"200"}"#);
+
+        let decoded: Labels = serde_json::from_str(r#"{"status":"200","endpoint":"/a"}"#).unwrap();
+        assert_eq!(decoded, labels);
+    }
+
+    #[test]
+    fn test_sample_roundtrip() {
+        let start = Instant::now();
+        let end = start + Duration::from_nanos(1_500);
+        let json = serde_json::to_string(&Sample::Timing("op".to_owned(), start, end, 3)).unwrap();
+        assert_eq!(json, r#"{"Timing":["op",1500,3]}"#);
+
+        match serde_json::from_str::<Sample<String>>(&json).unwrap() {
+            Sample::Timing(key, start, end, count) => {
+                assert_eq!(key, "op");
+                assert_eq!(end - start, Duration::from_nanos(1_500));
+                assert_eq!(count, 3);
+            },
+            sample => panic!("unexpected sample {:?}", sample),
+        }
+
+        // Reversed instants are serialized as an empty duration, rather than panicking.
+        let json = serde_json::to_string(&Sample::Timing("op".to_owned(), end, start, 1)).unwrap();
+        assert_eq!(json, r#"{"Timing":["op",0,1]}"#);
+
+        let json = serde_json::to_string(&Sample::TimingDuration("op".to_owned(), Duration::from_nanos(1_500), 2)).unwrap();
+        assert_eq!(json, r#"{"TimingDuration":["op",1500,2]}"#);
+        match serde_json::from_str::<Sample<String>>(&json).unwrap() {
+            Sample::TimingDuration(key, delta, count) => {
+                assert_eq!((key.as_str(), delta, count), ("op", Duration::from_nanos(1_500), 2));
+            },
+            sample => panic!("unexpected sample {:?}", sample),
+        }
+
+        let json = serde_json::to_string(&Sample::Count("op".to_owned(), -2)).unwrap();
+        assert_eq!(json, r#"{"Count":["op",-2]}"#);
+        match serde_json::from_str::<Sample<String>>(&json).unwrap() {
+            Sample::Count(key, value) => assert_eq!((key.as_str(), value), ("op", -2)),
+            sample => panic!("unexpected sample {:?}", sample),
+        }
+
+        let json = serde_json::to_string(&Sample::ValueDelta("op".to_owned(), -3)).unwrap();
+        assert_eq!(json, r#"{"ValueDelta":["op",-3]}"#);
+        match serde_json::from_str::<Sample<String>>(&json).unwrap() {
+            Sample::ValueDelta(key, delta) => assert_eq!((key.as_str(), delta), ("op", -3)),
+            sample => panic!("unexpected sample {:?}", sample),
+        }
+
+        let json = serde_json::to_string(&Sample::FloatValue("op".to_owned(), -0.5)).unwrap();
+        assert_eq!(json, r#"{"FloatValue":["op",-0.5]}"#);
+        match serde_json::from_str::<Sample<String>>(&json).unwrap() {
+            Sample::FloatValue(key, value) => assert_eq!((key.as_str(), value), ("op", -0.5)),
+            sample => panic!("unexpected sample {:?}", sample),
+        }
+    }
+
+    #[test]
+    fn test_facet_and_percentile_roundtrip() {
+        let json = serde_json::to_string(&Facet::TimingPercentile("op".to_owned())).unwrap();
+        assert_eq!(json, r#"{"TimingPercentile":"op"}"#);
+        let facet: Facet<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(facet, Facet::TimingPercentile("op".to_owned()));
+
+        let json = serde_json::to_string(&Percentile("p99".to_owned(), 99.0)).unwrap();
+        assert_eq!(json, r#"["p99",99.0]"#);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), Labels::new().with("endpoint", "/a")), 42);
+        snapshot.set_timing_percentiles("latency".to_owned(), h, &percentiles);
+        snapshot.set_internal_count("hotmic_dropped_samples", 1);
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let decoded: Snapshot<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.measurements().len(), 2);
+        assert_eq!(decoded.timing_percentile(&"latency".to_owned(), percentiles[0].clone()), Some(&100));
+        assert_eq!(decoded.internal_count("hotmic_dropped_samples"), Some(&1));
+        assert_eq!(decoded.measurements()[0].key().labels().get("endpoint"), Some("/a"));
+        assert_eq!(decoded.signed_data, snapshot.signed_data);
+        assert_eq!(decoded.unsigned_data, snapshot.unsigned_data);
+    }
+}
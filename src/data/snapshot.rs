@@ -0,0 +1,939 @@
+use std::hash::Hash;
+use std::fmt::{self, Display};
+use std::time::Duration;
+use fnv::FnvHashMap;
+use hdrhistogram::Histogram as HdrHistogram;
+use hdrhistogram::serialization::{Serializer, V2DeflateSerializer};
+use super::{Key, Labels, Percentile};
+
+/// A typed measurement held by a `Snapshot`.
+///
+/// Typed measurements retain the original metric key, its labels, and the kind of metric they came
+/// from, which allows consumers and exporters to interpret them without parsing flattened names.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TypedMeasurement<T> {
+    /// A counter value.
+    Count(Key<T>, i64),
+
+    /// A gauge value.
+    Gauge(Key<T>, i64),
+
+    /// A floating-point gauge value.
+    FloatGauge(Key<T>, f64),
+
+    /// Timing percentiles, in nanoseconds.
+    TimingPercentile(Key<T>, Summary),
+
+    /// Value percentiles.
+    ///
+    /// Values may have been scaled when recorded: see `Summary::scale`.
+    ValuePercentile(Key<T>, Summary),
+}
+
+impl<T> TypedMeasurement<T> {
+    /// Gets the metric key, and labels, of this measurement.
+    pub fn key(&self) -> &Key<T> {
+        match *self {
+            TypedMeasurement::Count(ref key, _) => key,
+            TypedMeasurement::Gauge(ref key, _) => key,
+            TypedMeasurement::FloatGauge(ref key, _) => key,
+            TypedMeasurement::TimingPercentile(ref key, _) => key,
+            TypedMeasurement::ValuePercentile(ref key, _) => key,
+        }
+    }
+}
+
+/// The change in a counter since the previous delta snapshot.
+///
+/// Holds the difference between the counter's value in this snapshot and its value in the
+/// previous snapshot taken by the same subscriber, along with that difference as a per-second
+/// rate over the interval between the two.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Delta<T> {
+    key: Key<T>,
+    delta: i64,
+    rate: f64,
+}
+
+impl<T> Delta<T> {
+    /// Gets the metric key, and labels, of the counter.
+    pub fn key(&self) -> &Key<T> {
+        &self.key
+    }
+
+    /// Gets the change in the counter's value.
+    pub fn delta(&self) -> i64 {
+        self.delta
+    }
+
+    /// Gets the change in the counter's value, per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+/// A summary of a histogram at a point in time.
+///
+/// Holds the configured percentiles, along with the number of samples, their sum, and the minimum
+/// and maximum values seen.  As values are extracted from an `HdrHistogram`, they are accurate to
+/// the precision of the underlying histogram.
+///
+/// Values are held as they were recorded, which, for histograms configured with
+/// `HistogramConfiguration::scale`, is multiplied by the scale.  The `scaled_` accessors divide
+/// the scale back out, giving values in their original units.
+///
+/// For histograms configured with `HistogramConfiguration::encoded`, the histogram itself is held
+/// too, so that it can be merged with those of other summaries via `MergedHistogram`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Summary {
+    percentiles: Vec<(Percentile, u64)>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+    #[cfg_attr(feature = "serde", serde(default = "unit_scale"))]
+    scale: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    encoded: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "serde")]
+fn unit_scale() -> f64 {
+    1.0
+}
+
+/// A value from a `Summary`, in the original units of its samples.
+///
+/// Unscaled values are kept exact, and are displayed as integers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Unscaled {
+    Exact(u64),
+    Float(f64),
+}
+
+impl Display for Unscaled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unscaled::Exact(value) => write!(f, "{}", value),
+            Unscaled::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Summary {
+    /// Creates a `Summary` from the given `HdrHistogram`, extracting all of the given `percentiles`.
+    pub fn from_histogram(h: &HdrHistogram<u64>, percentiles: &[Percentile]) -> Summary {
+        let percentiles = percentiles
+            .iter()
+            .map(|p| (p.clone(), h.value_at_percentile(p.1)))
+            .collect();
+
+        let sum = h
+            .iter_recorded()
+            .fold(0u64, |acc, v| acc.saturating_add(v.value_iterated_to().saturating_mul(v.count_at_value())));
+
+        Summary {
+            percentiles,
+            count: h.len(),
+            sum,
+            min: h.min(),
+            max: h.max(),
+            scale: 1.0,
+            encoded: None,
+        }
+    }
+
+    /// Holds on to the given histogram, encoded in the HdrHistogram V2 compressed format.
+    ///
+    /// If the histogram can't be encoded, the failure is logged as a warning, and the summary holds
+    /// no encoded histogram: `MergedHistogram::merge_summary` then fails with
+    /// `Error::InvalidHistogram`.  The receiver counts these failures in the
+    /// `hotmic_encoding_failures` internal metric.
+    pub(crate) fn with_encoded(mut self, h: &HdrHistogram<u64>) -> Summary {
+        let mut encoded = Vec::new();
+        match V2DeflateSerializer::new().serialize(h, &mut encoded) {
+            Ok(_) => self.encoded = Some(encoded),
+            Err(e) => warn!("failed to encode histogram: {:?}", e),
+        }
+        self
+    }
+
+    /// Marks the values of this summary as having been multiplied by `scale` when recorded.
+    pub(crate) fn with_scale(mut self, scale: f64) -> Summary {
+        self.scale = scale;
+        self
+    }
+
+    /// Gets all percentiles, and their values, held by this summary.
+    pub fn percentiles(&self) -> &[(Percentile, u64)] {
+        &self.percentiles
+    }
+
+    /// Gets the value of the percentile with the given label.
+    ///
+    /// Returns `None` if no percentile with the given label is held by this summary.
+    pub fn percentile(&self, label: &str) -> Option<&u64> {
+        self.percentiles
+            .iter()
+            .find(|(p, _)| p.0 == label)
+            .map(|(_, value)| value)
+    }
+
+    /// Gets the number of samples.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Gets the sum of all samples.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Gets the minimum sample value.
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    /// Gets the maximum sample value.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Gets the scale that values were multiplied by when recorded.
+    ///
+    /// This is `1.0` unless the histogram was configured with `HistogramConfiguration::scale`.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Gets the value of the percentile with the given label, in the original units.
+    ///
+    /// Returns `None` if no percentile with the given label is held by this summary.
+    pub fn scaled_percentile(&self, label: &str) -> Option<f64> {
+        self.percentile(label).map(|value| *value as f64 / self.scale)
+    }
+
+    /// Gets the sum of all samples, in the original units.
+    pub fn scaled_sum(&self) -> f64 {
+        self.sum as f64 / self.scale
+    }
+
+    /// Gets the minimum sample value, in the original units.
+    pub fn scaled_min(&self) -> f64 {
+        self.min as f64 / self.scale
+    }
+
+    /// Gets the maximum sample value, in the original units.
+    pub fn scaled_max(&self) -> f64 {
+        self.max as f64 / self.scale
+    }
+
+    /// Gets the histogram this summary was extracted from, in the HdrHistogram V2 compressed format.
+    ///
+    /// Returns `None` unless the histogram was configured with `HistogramConfiguration::encoded`, or
+    /// if it failed to encode.
+    pub fn encoded(&self) -> Option<&[u8]> {
+        self.encoded.as_deref()
+    }
+
+    /// Converts a value held by this summary back into the original units.
+    pub(crate) fn unscale(&self, value: u64) -> Unscaled {
+        if self.scale == 1.0 {
+            Unscaled::Exact(value)
+        } else {
+            Unscaled::Float(value as f64 / self.scale)
+        }
+    }
+}
+
+/// A point-in-time view of metric data.
+///
+/// Measurements are stored with their original metric keys and labels.  The flattened,
+/// string-keyed view used by earlier versions -- `{key}_count`, `{key}_ns_p99`, and so on -- is
+/// still kept up to date in `signed_data` and `unsigned_data`, with the labels of labeled series
+/// following the suffix, as in `{key}_count{code="200"}`.
+///
+/// Accessors that take a bare metric key, such as `count`, only look at the series without any
+/// labels: labeled series can be found via `measurements`.
+///
+/// Gauges are signed, so `set_value` and `value` deal in `i64` rather than the `u64` of 0.2.1 and
+/// earlier.  Non-negative gauges are still found in `unsigned_data`, and negative ones in
+/// `signed_data`.
+///
+/// Metrics about hotmic itself, such as the number of dropped samples, are only included when
+/// instrumentation is enabled.  They are kept apart from the measurements, and are named with a
+/// `hotmic_` prefix: these can be found via `internal_measurements`.
+///
+/// Snapshots retrieved via `Controller::get_delta_snapshot` additionally hold, for every counter,
+/// its change since the previous delta snapshot for the same subscriber: these can be found via
+/// `deltas`, and the interval they cover via `interval`.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Snapshot<T> {
+    measurements: Vec<TypedMeasurement<T>>,
+    internal: Vec<TypedMeasurement<String>>,
+    deltas: Vec<Delta<T>>,
+    interval: Option<Duration>,
+
+    /// All counter values, as `{key}_count`, and any negative integer gauge values, as
+    /// `{key}_value`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub signed_data: FnvHashMap<String, i64>,
+
+    /// All non-negative integer gauge values, as `{key}_value`, as well as all timing and value
+    /// percentiles, as `{key}_ns_{label}` and `{key}_value_{label}` respectively.  Value
+    /// percentiles are as recorded: see `Summary::scale`.
+    ///
+    /// Gauges only go below zero when adjusted with `Sample::ValueDelta`, so gauges set with
+    /// `Sample::Value` are always found here.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub unsigned_data: FnvHashMap<String, u64>,
+
+    // Where the measurements for each series are, so accessors don't have to search, and later
+    // measurements can take the place of earlier ones.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    index: FnvHashMap<T, FnvHashMap<Labels, Slots>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    internal_index: FnvHashMap<String, usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    delta_index: FnvHashMap<T, usize>,
+}
+
+/// The positions of the measurements for a series, by kind.
+#[derive(Clone, Copy, Default)]
+struct Slots {
+    count: Option<usize>,
+    gauge: Option<usize>,
+    timing: Option<usize>,
+    value: Option<usize>,
+}
+
+impl<T: Send + Eq + Hash + Send + Display + Clone> Default for Snapshot<T> {
+    fn default() -> Snapshot<T> {
+        Snapshot::new()
+    }
+}
+
+impl<T: Send + Eq + Hash + Send + Display + Clone> Snapshot<T> {
+    /// Creates an empty `Snapshot`.
+    pub fn new() -> Snapshot<T> {
+        Snapshot {
+            measurements: Vec::new(),
+            internal: Vec::new(),
+            deltas: Vec::new(),
+            interval: None,
+            signed_data: FnvHashMap::default(),
+            unsigned_data: FnvHashMap::default(),
+            index: FnvHashMap::default(),
+            internal_index: FnvHashMap::default(),
+            delta_index: FnvHashMap::default(),
+        }
+    }
+
+    /// Rebuilds a snapshot from its measurements, such as when deserializing one.
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_parts(
+        measurements: Vec<TypedMeasurement<T>>,
+        internal: Vec<TypedMeasurement<String>>,
+        deltas: Vec<Delta<T>>,
+        interval: Option<Duration>,
+    ) -> Snapshot<T> {
+        let mut snapshot = Snapshot::new();
+        for measurement in measurements {
+            snapshot.push(measurement);
+        }
+        for measurement in internal {
+            snapshot.push_internal(measurement);
+        }
+        snapshot.set_delta_values(deltas);
+        snapshot.interval = interval;
+        snapshot
+    }
+
+    /// Stores a counter value for the given metric key.
+    pub fn set_count<K: Into<Key<T>>>(&mut self, key: K, value: i64) {
+        self.push(TypedMeasurement::Count(key.into(), value));
+    }
+
+    /// Stores a gauge value for the given metric key.
+    ///
+    /// Gauges are signed: up to 0.2.1, this took, and `value` returned, a `u64`.
+    pub fn set_value<K: Into<Key<T>>>(&mut self, key: K, value: i64) {
+        self.push(TypedMeasurement::Gauge(key.into(), value));
+    }
+
+    /// Stores a floating-point gauge value for the given metric key.
+    pub fn set_float_value<K: Into<Key<T>>>(&mut self, key: K, value: f64) {
+        self.push(TypedMeasurement::FloatGauge(key.into(), value));
+    }
+
+    /// Sets timing percentiles for the given metric key.
+    ///
+    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
+    pub fn set_timing_percentiles<K: Into<Key<T>>>(&mut self, key: K, h: HdrHistogram<u64>, percentiles: &[Percentile]) {
+        let summary = Summary::from_histogram(&h, percentiles);
+        self.push(TypedMeasurement::TimingPercentile(key.into(), summary));
+    }
+
+    /// Sets value percentiles for the given metric key.
+    ///
+    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
+    pub fn set_value_percentiles<K: Into<Key<T>>>(&mut self, key: K, h: HdrHistogram<u64>, percentiles: &[Percentile]) {
+        let summary = Summary::from_histogram(&h, percentiles);
+        self.push(TypedMeasurement::ValuePercentile(key.into(), summary));
+    }
+
+    /// Sets value percentiles for the given metric key, from a histogram of scaled values.
+    ///
+    /// Like `set_value_percentiles`, but for an `HdrHistogram` holding values that were multiplied
+    /// by `scale` when recorded.  See `HistogramConfiguration::scale`.
+    pub fn set_scaled_value_percentiles<K: Into<Key<T>>>(
+        &mut self,
+        key: K,
+        h: HdrHistogram<u64>,
+        percentiles: &[Percentile],
+        scale: f64,
+    ) {
+        let summary = Summary::from_histogram(&h, percentiles).with_scale(scale);
+        self.push(TypedMeasurement::ValuePercentile(key.into(), summary));
+    }
+
+    /// Stores a summary of timing percentiles for the given metric key.
+    pub(crate) fn set_timing_summary(&mut self, key: Key<T>, summary: Summary) {
+        self.push(TypedMeasurement::TimingPercentile(key, summary));
+    }
+
+    /// Stores a summary of value percentiles for the given metric key.
+    pub(crate) fn set_value_summary(&mut self, key: Key<T>, summary: Summary) {
+        self.push(TypedMeasurement::ValuePercentile(key, summary));
+    }
+
+    /// Stores a measurement, keeping the flattened view and the index up to date.
+    ///
+    /// A later measurement of the same kind, for the same series, takes the place of an earlier
+    /// one, in the measurements as well as in the flattened view.
+    fn push(&mut self, measurement: TypedMeasurement<T>) {
+        match measurement {
+            TypedMeasurement::Count(ref key, value) => {
+                self.signed_data.insert(flatten(key, "count"), value);
+            },
+            TypedMeasurement::Gauge(ref key, value) => {
+                // A gauge that changes sign moves between the two, so only one holds it.
+                let name = flatten(key, "value");
+                if value < 0 {
+                    let _ = self.unsigned_data.remove(&name);
+                    self.signed_data.insert(name, value);
+                } else {
+                    let _ = self.signed_data.remove(&name);
+                    self.unsigned_data.insert(name, value as u64);
+                }
+            },
+            TypedMeasurement::FloatGauge(ref key, _) => {
+                // Floating-point gauges aren't flattened, so an integer value they replace goes.
+                let name = flatten(key, "value");
+                let _ = self.signed_data.remove(&name);
+                let _ = self.unsigned_data.remove(&name);
+            },
+            TypedMeasurement::TimingPercentile(ref key, ref summary) => {
+                for (percentile, value) in summary.percentiles() {
+                    self.unsigned_data.insert(flatten(key, &format!("ns_{}", percentile.0)), *value);
+                }
+            },
+            TypedMeasurement::ValuePercentile(ref key, ref summary) => {
+                for (percentile, value) in summary.percentiles() {
+                    self.unsigned_data.insert(flatten(key, &format!("value_{}", percentile.0)), *value);
+                }
+            },
+        }
+
+        let key = measurement.key();
+        let slots = self.index
+            .entry(key.name().clone())
+            .or_default()
+            .entry(key.labels().clone())
+            .or_default();
+        let slot = match measurement {
+            TypedMeasurement::Count(..) => &mut slots.count,
+            TypedMeasurement::Gauge(..) | TypedMeasurement::FloatGauge(..) => &mut slots.gauge,
+            TypedMeasurement::TimingPercentile(..) => &mut slots.timing,
+            TypedMeasurement::ValuePercentile(..) => &mut slots.value,
+        };
+        match *slot {
+            Some(position) => self.measurements[position] = measurement,
+            None => {
+                *slot = Some(self.measurements.len());
+                self.measurements.push(measurement);
+            },
+        }
+    }
+
+    /// Stores an internal measurement, keeping the index up to date.
+    fn push_internal(&mut self, measurement: TypedMeasurement<String>) {
+        match self.internal_index.get(measurement.key().name()) {
+            Some(&position) => self.internal[position] = measurement,
+            None => {
+                self.internal_index.insert(measurement.key().name().clone(), self.internal.len());
+                self.internal.push(measurement);
+            },
+        }
+    }
+
+    /// Stores an internal counter value.
+    pub(crate) fn set_internal_count(&mut self, name: &str, value: i64) {
+        self.push_internal(TypedMeasurement::Count(Key::from(name.to_owned()), value));
+    }
+
+    /// Stores counter deltas, and the interval they were accumulated over.
+    ///
+    /// Rates are computed from the interval, and are zero if the interval is empty.
+    pub(crate) fn set_deltas(&mut self, interval: Duration, deltas: Vec<(Key<T>, i64)>) {
+        let secs = interval.as_secs() as f64 + f64::from(interval.subsec_nanos()) / 1e9;
+        let deltas = deltas
+            .into_iter()
+            .map(|(key, delta)| {
+                let rate = if secs > 0.0 { delta as f64 / secs } else { 0.0 };
+                Delta { key, delta, rate }
+            })
+            .collect();
+        self.set_delta_values(deltas);
+        self.interval = Some(interval);
+    }
+
+    fn set_delta_values(&mut self, deltas: Vec<Delta<T>>) {
+        self.delta_index = deltas
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.key.labels().is_empty())
+            .map(|(i, d)| (d.key.name().clone(), i))
+            .collect();
+        self.deltas = deltas;
+    }
+
+    /// Gets the interval covered by the counter deltas held by this snapshot.
+    ///
+    /// Returns `None` if this is not a delta snapshot.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Gets the counter deltas held by this snapshot.
+    ///
+    /// Empty if this is not a delta snapshot.
+    pub fn deltas(&self) -> &[Delta<T>] {
+        &self.deltas
+    }
+
+    /// Gets the counter delta for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no counter delta in this snapshot.
+    pub fn count_delta(&self, key: &T) -> Option<&Delta<T>> {
+        self.delta_index.get(key).map(|i| &self.deltas[*i])
+    }
+
+    /// Stores an internal gauge value.
+    pub(crate) fn set_internal_value(&mut self, name: &str, value: u64) {
+        let value = value.min(i64::MAX as u64) as i64;
+        self.push_internal(TypedMeasurement::Gauge(Key::from(name.to_owned()), value));
+    }
+
+    /// Gets the internal measurements held by this snapshot.
+    pub fn internal_measurements(&self) -> &[TypedMeasurement<String>] {
+        &self.internal
+    }
+
+    /// Gets an internal counter value, such as `hotmic_dropped_samples`.
+    pub fn internal_count(&self, name: &str) -> Option<&i64> {
+        match self.internal_index.get(name).map(|i| &self.internal[*i]) {
+            Some(TypedMeasurement::Count(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets an internal gauge value, such as `hotmic_buffer_pool_available`.
+    pub fn internal_value(&self, name: &str) -> Option<&i64> {
+        match self.internal_index.get(name).map(|i| &self.internal[*i]) {
+            Some(TypedMeasurement::Gauge(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the typed measurements held by this snapshot.
+    pub fn measurements(&self) -> &[TypedMeasurement<T>] {
+        &self.measurements
+    }
+
+    /// Gets the measurement in the given slot for the given metric key.
+    fn slot<F>(&self, key: &T, f: F) -> Option<&TypedMeasurement<T>>
+        where F: Fn(&Slots) -> Option<usize>
+    {
+        self.index
+            .get(key)
+            .and_then(|series| series.get(&Labels::new()))
+            .and_then(f)
+            .map(|i| &self.measurements[i])
+    }
+
+    /// Gets the counter value for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no counter value in this snapshot.
+    pub fn count(&self, key: &T) -> Option<&i64> {
+        match self.slot(key, |s| s.count) {
+            Some(TypedMeasurement::Count(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the gauge value for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no gauge value in this snapshot, or if its gauge holds
+    /// a floating-point value: see `float_value`.
+    ///
+    /// Gauges are signed, so this returns an `i64`, rather than the `u64` of 0.2.1 and earlier:
+    /// gauge values sent with `Sample::Value` above `i64::MAX` are clamped to it.
+    pub fn value(&self, key: &T) -> Option<&i64> {
+        match self.slot(key, |s| s.gauge) {
+            Some(TypedMeasurement::Gauge(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the gauge value for the given metric key, as a floating-point value.
+    ///
+    /// Returns both floating-point gauge values and integer ones, converted.  Returns `None` if
+    /// the metric key has no gauge value in this snapshot.
+    pub fn float_value(&self, key: &T) -> Option<f64> {
+        match self.slot(key, |s| s.gauge) {
+            Some(TypedMeasurement::Gauge(_, value)) => Some(*value as f64),
+            Some(TypedMeasurement::FloatGauge(_, value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Gets the timing summary for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no timing percentiles in this snapshot.
+    pub fn timing_summary(&self, key: &T) -> Option<&Summary> {
+        match self.slot(key, |s| s.timing) {
+            Some(TypedMeasurement::TimingPercentile(_, summary)) => Some(summary),
+            _ => None,
+        }
+    }
+
+    /// Gets the value summary for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no value percentiles in this snapshot.
+    pub fn value_summary(&self, key: &T) -> Option<&Summary> {
+        match self.slot(key, |s| s.value) {
+            Some(TypedMeasurement::ValuePercentile(_, summary)) => Some(summary),
+            _ => None,
+        }
+    }
+
+    /// Gets the given timing percentile for given metric key.
+    ///
+    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
+    pub fn timing_percentile(&self, key: &T, percentile: Percentile) -> Option<&u64> {
+        self.timing_summary(key).and_then(|s| s.percentile(&percentile.0))
+    }
+
+    /// Gets the given value percentile for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
+    pub fn value_percentile(&self, key: &T, percentile: Percentile) -> Option<&u64> {
+        self.value_summary(key).and_then(|s| s.percentile(&percentile.0))
+    }
+}
+
+/// Flattens a metric key into a single name, with the given suffix, as `{key}_{suffix}{labels}`.
+fn flatten<T: Display>(key: &Key<T>, suffix: &str) -> String {
+    format!("{}_{}{}", key.name(), suffix, key.labels())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, TypedMeasurement};
+    use data::{Key, Labels, Percentile};
+    use hdrhistogram::Histogram;
+    use std::time::Duration;
+
+    #[test]
+    fn test_snapshot_simple_set_and_get() {
+        let key = "ok".to_owned();
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count(key.clone(), 1);
+        snapshot.set_value(key.clone(), 42);
+
+        assert_eq!(snapshot.count(&key).unwrap(), &1);
+        assert_eq!(snapshot.value(&key).unwrap(), &42);
+
+        // A later value for the same series takes the place of the earlier one.
+        snapshot.set_count(key.clone(), 2);
+        assert_eq!(snapshot.count(&key).unwrap(), &2);
+        assert_eq!(snapshot.signed_data.get("ok_count"), Some(&2));
+    }
+
+    #[test]
+    fn test_snapshot_percentiles() {
+        let mut snapshot = Snapshot::new();
+
+        {
+            let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+            h1.saturating_record(500_000);
+            h1.saturating_record(750_000);
+            h1.saturating_record(1_000_000);
+            h1.saturating_record(1_250_000);
+
+            let tkey = "ok".to_owned();
+            let tpercentiles = vec![
+                Percentile("min".to_owned(), 0.0),
+                Percentile("p50".to_owned(), 50.0),
+                Percentile("p99".to_owned(), 99.0),
+                Percentile("max".to_owned(), 100.0),
+            ];
+
+            snapshot.set_timing_percentiles(tkey.clone(), h1, &tpercentiles);
+
+            let min_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[0].clone());
+            let p50_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[1].clone());
+            let p99_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[2].clone());
+            let max_tpercentile = snapshot.timing_percentile(&tkey, tpercentiles[3].clone());
+            let fake_tpercentile = snapshot.timing_percentile(&tkey, Percentile("fake".to_owned(), 63.0));
+
+            assert!(min_tpercentile.is_some());
+            assert!(p50_tpercentile.is_some());
+            assert!(p99_tpercentile.is_some());
+            assert!(max_tpercentile.is_some());
+            assert!(fake_tpercentile.is_none());
+        }
+
+        {
+            let mut h2 = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+            h2.saturating_record(500_000);
+            h2.saturating_record(750_000);
+            h2.saturating_record(1_000_000);
+            h2.saturating_record(1_250_000);
+
+            let vkey = "ok".to_owned();
+            let vpercentiles = vec![
+                Percentile("min".to_owned(), 0.0),
+                Percentile("p50".to_owned(), 50.0),
+                Percentile("p99".to_owned(), 99.0),
+                Percentile("max".to_owned(), 100.0),
+            ];
+
+            snapshot.set_value_percentiles(vkey.clone(), h2, &vpercentiles);
+
+            let min_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[0].clone());
+            let p50_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[1].clone());
+            let p99_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[2].clone());
+            let max_vpercentile = snapshot.value_percentile(&vkey, vpercentiles[3].clone());
+            let fake_vpercentile = snapshot.value_percentile(&vkey, Percentile("fake".to_owned(), 63.0));
+
+            assert!(min_vpercentile.is_some());
+            assert!(p50_vpercentile.is_some());
+            assert!(p99_vpercentile.is_some());
+            assert!(max_vpercentile.is_some());
+            assert!(fake_vpercentile.is_none());
+
+        }
+    }
+
+    #[test]
+    fn test_snapshot_summary() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+        h.saturating_record(200);
+        h.saturating_record(300);
+
+        let key = "ok".to_owned();
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+        let mut snapshot = Snapshot::new();
+        snapshot.set_timing_percentiles(key.clone(), h, &percentiles);
+
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!(summary.count(), 3);
+        assert_eq!(summary.sum(), 600);
+        assert_eq!(summary.min(), 100);
+        assert_eq!(summary.max(), 300);
+        assert_eq!(summary.percentile("p50"), Some(&200));
+        assert!(snapshot.value_summary(&key).is_none());
+
+        match snapshot.measurements()[0] {
+            TypedMeasurement::TimingPercentile(ref k, _) => assert_eq!(k.name(), &key),
+            ref m => panic!("unexpected measurement {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_scaled_values() {
+        // Values of 0.25, 0.5 and 0.75, recorded with a scale of 1000.
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(250);
+        h.saturating_record(500);
+        h.saturating_record(750);
+
+        let key = "ratio".to_owned();
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+        let mut snapshot = Snapshot::new();
+        snapshot.set_scaled_value_percentiles(key.clone(), h, &percentiles, 1000.0);
+        snapshot.set_float_value(key.clone(), -0.5);
+
+        let summary = snapshot.value_summary(&key).unwrap();
+        assert_eq!(summary.scale(), 1000.0);
+        assert_eq!(summary.percentile("p50"), Some(&500));
+        assert_eq!(summary.scaled_percentile("p50"), Some(0.5));
+        assert_eq!(summary.scaled_sum(), 1.5);
+        assert_eq!(summary.scaled_min(), 0.25);
+        assert_eq!(summary.scaled_max(), 0.75);
+
+        assert_eq!(snapshot.float_value(&key), Some(-0.5));
+        assert!(snapshot.value(&key).is_none());
+
+        // Integer gauges are converted.
+        snapshot.set_value("count".to_owned(), 3);
+        assert_eq!(snapshot.float_value(&"count".to_owned()), Some(3.0));
+    }
+
+    #[test]
+    fn test_snapshot_internal_measurements() {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("hotmic_dropped_samples".to_owned(), 7);
+        snapshot.set_internal_count("hotmic_dropped_samples", 3);
+        snapshot.set_internal_value("hotmic_buffer_pool_available", 5);
+
+        assert_eq!(snapshot.measurements().len(), 1);
+        assert_eq!(snapshot.internal_measurements().len(), 2);
+        assert_eq!(snapshot.internal_value("hotmic_buffer_pool_available"), Some(&5));
+        assert_eq!(snapshot.internal_value("hotmic_dropped_samples"), None);
+        assert_eq!(snapshot.count(&"hotmic_dropped_samples".to_owned()), Some(&7));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&3));
+        assert_eq!(snapshot.internal_count("unknown"), None);
+        assert!(!snapshot.signed_data.values().any(|v| *v == 3));
+    }
+
+    #[test]
+    fn test_snapshot_deltas() {
+        let key = "ok".to_owned();
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count(key.clone(), 10);
+        assert_eq!(snapshot.interval(), None);
+        assert!(snapshot.count_delta(&key).is_none());
+
+        let labeled = Key::new(key.clone(), Labels::new().with("status", "500"));
+        snapshot.set_deltas(Duration::from_millis(500), vec![(key.clone().into(), 4), (labeled, -1)]);
+        assert_eq!(snapshot.interval(), Some(Duration::from_millis(500)));
+        assert_eq!(snapshot.deltas().len(), 2);
+
+        let delta = snapshot.count_delta(&key).unwrap();
+        assert_eq!(delta.delta(), 4);
+        assert_eq!(delta.rate(), 8.0);
+        assert_eq!(snapshot.deltas()[1].rate(), -2.0);
+
+        snapshot.set_deltas(Duration::new(0, 0), vec![(key.clone().into(), 4)]);
+        assert_eq!(snapshot.count_delta(&key).unwrap().rate(), 0.0);
+    }
+
+    #[test]
+    fn test_snapshot_flattened_view() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(42);
+
+        let key = "ok".to_owned();
+        let percentiles = vec![Percentile("max".to_owned(), 100.0)];
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count(key.clone(), 1);
+        snapshot.set_value(key.clone(), -7);
+        snapshot.set_timing_percentiles(key.clone(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles(key, h, &percentiles);
+
+        let signed = &snapshot.signed_data;
+        assert_eq!(signed.len(), 2);
+        assert_eq!(signed.get("ok_count"), Some(&1));
+        assert_eq!(signed.get("ok_value"), Some(&-7));
+
+        let unsigned = &snapshot.unsigned_data;
+        assert_eq!(unsigned.len(), 2);
+        assert_eq!(unsigned.get("ok_ns_max"), Some(&42));
+        assert_eq!(unsigned.get("ok_value_max"), Some(&42));
+    }
+
+    #[test]
+    fn test_snapshot_flattened_gauges() {
+        // Gauges have always been found in the unsigned view, and only negative ones aren't.
+        let mut snapshot = Snapshot::new();
+        snapshot.set_value("connections".to_owned(), 42);
+        snapshot.set_value("zero".to_owned(), 0);
+        snapshot.set_value("balance".to_owned(), -3);
+        snapshot.set_float_value("load".to_owned(), 0.5);
+
+        assert_eq!(snapshot.unsigned_data.get("connections_value"), Some(&42));
+        assert_eq!(snapshot.unsigned_data.get("zero_value"), Some(&0));
+        assert_eq!(snapshot.signed_data.get("balance_value"), Some(&-3));
+        assert_eq!(snapshot.unsigned_data.len(), 2);
+        assert_eq!(snapshot.signed_data.len(), 1);
+
+        // A later value that changes sign moves the gauge over.
+        snapshot.set_value("balance".to_owned(), 3);
+        assert_eq!(snapshot.unsigned_data.get("balance_value"), Some(&3));
+        assert!(snapshot.signed_data.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_replaced_measurements() {
+        let key = "ok".to_owned();
+        let labeled = Key::new(key.clone(), Labels::new().with("code", "200"));
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count(key.clone(), 1);
+        snapshot.set_count(labeled.clone(), 2);
+        snapshot.set_value(key.clone(), 3);
+
+        // A later measurement of the same kind, for the same series, takes the earlier one's place.
+        snapshot.set_count(key.clone(), 4);
+        snapshot.set_count(labeled, 5);
+        snapshot.set_float_value(key.clone(), 0.5);
+        assert_eq!(snapshot.measurements().len(), 3);
+        assert_eq!(snapshot.count(&key), Some(&4));
+        assert_eq!(snapshot.float_value(&key), Some(0.5));
+        assert_eq!(snapshot.signed_data.get("ok_count{code=\"200\"}"), Some(&5));
+        assert!(!snapshot.unsigned_data.contains_key("ok_value"));
+
+        snapshot.set_internal_count("hotmic_dropped_samples", 1);
+        snapshot.set_internal_count("hotmic_dropped_samples", 2);
+        assert_eq!(snapshot.internal_measurements().len(), 1);
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&2));
+    }
+
+    #[test]
+    fn test_snapshot_labeled_series() {
+        let key = "ok".to_owned();
+        let labels = Labels::new().with("code", "200");
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count(key.clone(), 1);
+        snapshot.set_count(Key::new(key.clone(), labels.clone()), 2);
+
+        // Bare accessors only see the unlabeled series.
+        assert_eq!(snapshot.count(&key), Some(&1));
+        assert_eq!(snapshot.measurements().len(), 2);
+
+        match snapshot.measurements()[1] {
+            TypedMeasurement::Count(ref k, value) => {
+                assert_eq!(k.labels(), &labels);
+                assert_eq!(value, 2);
+            },
+            ref m => panic!("unexpected measurement {:?}", m),
+        }
+
+        let signed = &snapshot.signed_data;
+        assert_eq!(signed.get("ok_count"), Some(&1));
+        assert_eq!(signed.get("ok_count{code=\"200\"}"), Some(&2));
+
+        // Suffixes go on the name, ahead of the labels.
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(42);
+        let percentiles = vec![Percentile("max".to_owned(), 100.0)];
+        snapshot.set_timing_percentiles(Key::new(key, labels), h, &percentiles);
+        assert_eq!(snapshot.unsigned_data.get("ok_ns_max{code=\"200\"}"), Some(&42));
+    }
+}
@@ -0,0 +1,93 @@
+use std::error;
+use std::fmt;
+use std::io;
+use channel::SendError;
+
+/// Errors that can occur when sending samples to, or making requests of, a `Receiver`.
+#[derive(Debug)]
+pub enum Error {
+    /// No sample buffer was available without blocking.
+    BufferPoolExhausted,
+
+    /// The channel to the receiver was full.
+    ChannelFull,
+
+    /// The receiver has shut down, or has been dropped.
+    ReceiverGone,
+
+    /// The receiver did not respond with a snapshot in time.
+    SnapshotTimeout,
+
+    /// An encoded histogram was missing, or could not be decoded.
+    InvalidHistogram,
+
+    /// A histogram could not be merged, as it was recorded with a different scale.
+    IncompatibleHistogram,
+
+    /// An I/O error occurred.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BufferPoolExhausted => write!(f, "no sample buffer available"),
+            Error::ChannelFull => write!(f, "channel to receiver is full"),
+            Error::ReceiverGone => write!(f, "receiver is gone"),
+            Error::SnapshotTimeout => write!(f, "timed out waiting for snapshot"),
+            Error::InvalidHistogram => write!(f, "encoded histogram is missing or invalid"),
+            Error::IncompatibleHistogram => write!(f, "histogram was recorded with a different scale"),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl<T> From<SendError<T>> for Error {
+    fn from(e: SendError<T>) -> Error {
+        match e {
+            SendError::Io(e) => Error::Io(e),
+            SendError::Full(_) => Error::ChannelFull,
+            SendError::Disconnected(_) => Error::ReceiverGone,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use channel::SendError;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn test_error_from_send_error() {
+        match Error::from(SendError::Full(())) {
+            Error::ChannelFull => {},
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match Error::from(SendError::Disconnected(())) {
+            Error::ReceiverGone => {},
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let e = Error::from(SendError::<()>::Io(io::Error::other("boom")));
+        assert_eq!(e.to_string(), "boom");
+        assert!(e.source().is_some());
+    }
+}
@@ -0,0 +1,191 @@
+//! Graphite plaintext push exporter over TCP.
+//!
+//! Every measurement is rendered as one or more `path value timestamp` lines, with the path
+//! derived from the metric key: counters as `{key}.count`, gauges as `{key}.value`, timing
+//! percentiles as `{key}.ns.{label}` and value percentiles as `{key}.distribution.{label}`.
+//! Percentiles also get the `count` and `sum` of their underlying histogram.
+//!
+//! Graphite paths have no notion of labels, so each label is appended to the metric key as
+//! `.{name}.{value}`.
+use std::fmt::{Display, Write};
+use std::hash::Hash;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use control::Controller;
+use observer::Observer;
+use data::{Labels, Snapshot, Summary, TypedMeasurement};
+use super::{log_export, push_every, since_epoch, Connection};
+
+/// Pushes snapshots to Graphite over TCP.
+pub struct Exporter {
+    connection: Connection,
+    prefix: Option<String>,
+}
+
+impl Exporter {
+    /// Creates a new `Exporter` that sends to the given address.
+    ///
+    /// The connection is established when first exporting, and re-established if it fails.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Exporter> {
+        Ok(Exporter {
+            connection: Connection::new(addr)?,
+            prefix: None,
+        })
+    }
+
+    /// Sets a prefix for all metric paths.
+    ///
+    /// The prefix is separated from metric paths with a dot.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Renders a snapshot into Graphite plaintext lines, using the given timestamp in seconds.
+    pub fn render<T: Send + Eq + Hash + Display + Clone>(&self, snapshot: &Snapshot<T>, timestamp: u64) -> String {
+        let mut output = String::new();
+        self.render_measurements(&mut output, snapshot.measurements(), timestamp);
+        self.render_measurements(&mut output, snapshot.internal_measurements(), timestamp);
+        output
+    }
+
+    /// Renders and sends a snapshot, timestamped with the current time.
+    pub fn export<T: Send + Eq + Hash + Display + Clone>(&mut self, snapshot: &Snapshot<T>) -> io::Result<()> {
+        let output = self.render(snapshot, since_epoch().as_secs());
+        self.connection.write_all(output.as_bytes())
+    }
+
+    /// Exports a snapshot, retrieved via `controller`, every `interval`.
+    ///
+    /// See the [module documentation](../index.html) for how pushing behaves.
+    pub fn run<T: Send + Eq + Hash + Display + Clone>(&mut self, controller: &Controller<T>, interval: Duration) {
+        push_every("graphite", controller, interval, |snapshot| self.export(snapshot));
+    }
+
+    fn render_measurements<T: Display>(&self, output: &mut String, measurements: &[TypedMeasurement<T>], timestamp: u64) {
+        for measurement in measurements {
+            let key = measurement.key();
+            let path = self.path(key.name(), key.labels());
+            match *measurement {
+                TypedMeasurement::Count(_, value) => {
+                    let _ = writeln!(output, "{}.count {} {}", path, value, timestamp);
+                },
+                TypedMeasurement::Gauge(_, value) => {
+                    let _ = writeln!(output, "{}.value {} {}", path, value, timestamp);
+                },
+                TypedMeasurement::FloatGauge(_, value) => {
+                    let _ = writeln!(output, "{}.value {} {}", path, value, timestamp);
+                },
+                TypedMeasurement::TimingPercentile(_, ref summary) => {
+                    write_summary(output, &format!("{}.ns", path), summary, timestamp);
+                },
+                TypedMeasurement::ValuePercentile(_, ref summary) => {
+                    write_summary(output, &format!("{}.distribution", path), summary, timestamp);
+                },
+            }
+        }
+    }
+
+    fn path<T: Display>(&self, key: &T, labels: &Labels) -> String {
+        let mut path = String::new();
+        if let Some(ref prefix) = self.prefix {
+            path.push_str(&sanitize(prefix));
+            path.push('.');
+        }
+        path.push_str(&sanitize(key));
+
+        for (label, value) in labels.iter() {
+            let _ = write!(path, ".{}.{}", sanitize(&label).replace('.', "_"), sanitize(&value).replace('.', "_"));
+        }
+
+        path
+    }
+}
+
+impl<T: Send + Eq + Hash + Display + Clone> Observer<T> for Exporter {
+    fn observe(&mut self, snapshot: &Snapshot<T>) {
+        log_export("graphite", self.export(snapshot));
+    }
+}
+
+fn write_summary(output: &mut String, path: &str, summary: &Summary, timestamp: u64) {
+    for (percentile, value) in summary.percentiles() {
+        let _ = writeln!(output, "{}.{} {} {}", path, sanitize(&percentile.0).replace('.', "_"), summary.unscale(*value), timestamp);
+    }
+    let _ = writeln!(output, "{}.count {} {}", path, summary.count(), timestamp);
+    let _ = writeln!(output, "{}.sum {} {}", path, summary.unscale(summary.sum()), timestamp);
+}
+
+/// Replaces any character outside of `[a-zA-Z0-9_.-]` with an underscore.
+fn sanitize<T: Display>(s: &T) -> String {
+    s.to_string()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize, Exporter};
+    use data::{Key, Labels, Percentile, Snapshot};
+    use hdrhistogram::Histogram;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_graphite_render() {
+        let exporter = Exporter::new("127.0.0.1:2003").unwrap().prefix("app");
+
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(1_500);
+        let percentiles = vec![Percentile("p99".to_owned(), 99.0)];
+        let labels = Labels::new().with("endpoint", "/a.b");
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        snapshot.set_value(Key::new("connections".to_owned(), labels), 7);
+        snapshot.set_timing_percentiles("db.latency".to_owned(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles("size".to_owned(), h, &percentiles);
+
+        let output = exporter.render(&snapshot, 1_500_000_000);
+        assert_eq!(output, "\
+            app.requests.count 42 1500000000\n\
+            app.connections.endpoint._a_b.value 7 1500000000\n\
+            app.db.latency.ns.p99 1500 1500000000\n\
+            app.db.latency.ns.count 1 1500000000\n\
+            app.db.latency.ns.sum 1500 1500000000\n\
+            app.size.distribution.p99 1500 1500000000\n\
+            app.size.distribution.count 1 1500000000\n\
+            app.size.distribution.sum 1500 1500000000\n");
+    }
+
+    #[test]
+    fn test_graphite_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = Exporter::new(listener.local_addr().unwrap()).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        exporter.export(&snapshot).unwrap();
+        drop(exporter);
+
+        let received = handle.join().unwrap();
+        assert!(received.starts_with("requests.count 42 "));
+        assert!(received.ends_with("\n"));
+    }
+
+    #[test]
+    fn test_graphite_sanitize() {
+        assert_eq!(sanitize(&"db.query latency"), "db.query_latency");
+        assert_eq!(sanitize(&"a/b;c=d"), "a_b_c_d");
+    }
+}
@@ -0,0 +1,236 @@
+//! InfluxDB line protocol push exporter over TCP.
+//!
+//! Every measurement is rendered as a single line, written to one configurable measurement name.
+//! The metric key is carried in the `metric` tag, alongside a tag for every label; a label named
+//! `metric` is carried in an `exported_metric` tag instead, as it would collide.  Counters are
+//! rendered as a `count` field, gauges as a `value` field, and timing and value percentiles as a
+//! field per percentile -- `timing_p99` or `distribution_p99`, say -- along with the `_count` and
+//! `_sum` of their underlying histogram.  Timings are in nanoseconds.
+//!
+//! A field always holds values of the same type, as InfluxDB rejects writes that change it:
+//! floating-point gauges are rendered as a `value_float` field instead, and percentiles of scaled
+//! values as `distribution_float_p99` and `distribution_float_sum`.  Floating-point gauges that
+//! are NaN or infinite can't be represented at all, and are skipped.
+use std::fmt::{Display, Write};
+use std::hash::Hash;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use control::Controller;
+use observer::Observer;
+use data::{Labels, Snapshot, Summary, TypedMeasurement, Unscaled};
+use helper::duration_as_nanos;
+use super::{log_export, push_every, since_epoch, Connection};
+
+/// Pushes snapshots to an InfluxDB-compatible endpoint over TCP.
+pub struct Exporter {
+    connection: Connection,
+    measurement: String,
+}
+
+impl Exporter {
+    /// Creates a new `Exporter` that sends to the given address.
+    ///
+    /// The connection is established when first exporting, and re-established if it fails.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Exporter> {
+        Ok(Exporter {
+            connection: Connection::new(addr)?,
+            measurement: "hotmic".to_owned(),
+        })
+    }
+
+    /// Sets the measurement name.
+    ///
+    /// Defaults to `hotmic`.
+    pub fn measurement(mut self, measurement: &str) -> Self {
+        self.measurement = measurement.to_owned();
+        self
+    }
+
+    /// Renders a snapshot into line protocol, using the given timestamp in nanoseconds.
+    pub fn render<T: Send + Eq + Hash + Display + Clone>(&self, snapshot: &Snapshot<T>, timestamp: u64) -> String {
+        let mut output = String::new();
+        self.render_measurements(&mut output, snapshot.measurements(), timestamp);
+        self.render_measurements(&mut output, snapshot.internal_measurements(), timestamp);
+        output
+    }
+
+    /// Renders and sends a snapshot, timestamped with the current time.
+    pub fn export<T: Send + Eq + Hash + Display + Clone>(&mut self, snapshot: &Snapshot<T>) -> io::Result<()> {
+        let output = self.render(snapshot, duration_as_nanos(since_epoch()));
+        self.connection.write_all(output.as_bytes())
+    }
+
+    /// Exports a snapshot, retrieved via `controller`, every `interval`.
+    ///
+    /// See the [module documentation](../index.html) for how pushing behaves.
+    pub fn run<T: Send + Eq + Hash + Display + Clone>(&mut self, controller: &Controller<T>, interval: Duration) {
+        push_every("influx", controller, interval, |snapshot| self.export(snapshot));
+    }
+
+    fn render_measurements<T: Display>(&self, output: &mut String, measurements: &[TypedMeasurement<T>], timestamp: u64) {
+        for measurement in measurements {
+            let key = measurement.key();
+            let series = self.series(key.name(), key.labels());
+            let fields = match *measurement {
+                TypedMeasurement::Count(_, value) => format!("count={}i", value),
+                TypedMeasurement::Gauge(_, value) => format!("value={}i", value),
+                TypedMeasurement::FloatGauge(_, value) if value.is_finite() => format!("value_float={}", value),
+                TypedMeasurement::FloatGauge(..) => continue,
+                TypedMeasurement::TimingPercentile(_, ref summary) => summary_fields("timing", summary),
+                TypedMeasurement::ValuePercentile(_, ref summary) => summary_fields("distribution", summary),
+            };
+            let _ = writeln!(output, "{} {} {}", series, fields, timestamp);
+        }
+    }
+
+    fn series<T: Display>(&self, key: &T, labels: &Labels) -> String {
+        let mut series = escape(&self.measurement, &[',', ' ']);
+        let _ = write!(series, ",metric={}", escape(key, &[',', '=', ' ']));
+        for (label, value) in labels.iter() {
+            let label = if label == "metric" { "exported_metric" } else { label };
+            let _ = write!(series, ",{}={}", escape(&label, &[',', '=', ' ']), escape(&value, &[',', '=', ' ']));
+        }
+        series
+    }
+}
+
+impl<T: Send + Eq + Hash + Display + Clone> Observer<T> for Exporter {
+    fn observe(&mut self, snapshot: &Snapshot<T>) {
+        log_export("influx", self.export(snapshot));
+    }
+}
+
+fn summary_fields(kind: &str, summary: &Summary) -> String {
+    // Scaled values are floats, so they get fields of their own.
+    let valued = if summary.scale() == 1.0 { kind.to_owned() } else { format!("{}_float", kind) };
+    let mut fields = summary
+        .percentiles()
+        .iter()
+        .map(|(percentile, value)| format!("{}_{}={}", valued, escape(&percentile.0, &[',', '=', ' ']), number(summary.unscale(*value))))
+        .collect::<Vec<_>>();
+    fields.push(format!("{}_count={}", kind, integer(summary.count())));
+    fields.push(format!("{}_sum={}", valued, number(summary.unscale(summary.sum()))));
+    fields.join(",")
+}
+
+/// Renders an unsigned value as a line protocol integer, saturating at the largest signed value.
+fn integer(value: u64) -> String {
+    format!("{}i", value.min(i64::MAX as u64))
+}
+
+/// Renders a summary value as a line protocol integer, or as a float if it was scaled.
+fn number(value: Unscaled) -> String {
+    match value {
+        Unscaled::Exact(value) => integer(value),
+        Unscaled::Float(value) => value.to_string(),
+    }
+}
+
+/// Escapes the given characters, and backslashes, with a backslash.
+fn escape<T: Display>(s: &T, special: &[char]) -> String {
+    let mut escaped = String::new();
+    for c in s.to_string().chars() {
+        // Newlines can't be escaped, and would end the line early.
+        let c = if c == '\n' { ' ' } else { c };
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, integer, Exporter};
+    use data::{Key, Labels, Percentile, Snapshot};
+    use hdrhistogram::Histogram;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_influx_render() {
+        let exporter = Exporter::new("127.0.0.1:8094").unwrap().measurement("app");
+
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(1_500);
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0), Percentile("p99".to_owned(), 99.0)];
+        let labels = Labels::new().with("endpoint", "/a b");
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), labels), 42);
+        snapshot.set_value("connections".to_owned(), 7);
+        snapshot.set_timing_percentiles("latency".to_owned(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles("size".to_owned(), h, &percentiles);
+
+        let output = exporter.render(&snapshot, 1_500_000_000_000_000_000);
+        assert_eq!(output, "\
+            app,metric=requests,endpoint=/a\\ b count=42i 1500000000000000000\n\
+            app,metric=connections value=7i 1500000000000000000\n\
+            app,metric=latency timing_p50=1500i,timing_p99=1500i,timing_count=1i,timing_sum=1500i 1500000000000000000\n\
+            app,metric=size distribution_p50=1500i,distribution_p99=1500i,distribution_count=1i,distribution_sum=1500i \
+            1500000000000000000\n");
+    }
+
+    #[test]
+    fn test_influx_float_fields() {
+        let exporter = Exporter::new("127.0.0.1:8094").unwrap();
+
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(250);
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_float_value("load".to_owned(), 1.0);
+        snapshot.set_float_value("nan".to_owned(), f64::NAN);
+        snapshot.set_float_value("inf".to_owned(), f64::INFINITY);
+        snapshot.set_scaled_value_percentiles("ratio".to_owned(), h, &percentiles, 1000.0);
+
+        let output = exporter.render(&snapshot, 1);
+        assert_eq!(output, "\
+            hotmic,metric=load value_float=1 1\n\
+            hotmic,metric=ratio distribution_float_p50=0.25,distribution_count=1i,distribution_float_sum=0.25 1\n");
+    }
+
+    #[test]
+    fn test_influx_metric_label() {
+        let exporter = Exporter::new("127.0.0.1:8094").unwrap();
+        let labels = Labels::new().with("metric", "other").with("endpoint", "/a");
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), labels), 42);
+
+        let output = exporter.render(&snapshot, 1);
+        assert_eq!(output, "hotmic,metric=requests,endpoint=/a,exported_metric=other count=42i 1\n");
+    }
+
+    #[test]
+    fn test_influx_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = Exporter::new(listener.local_addr().unwrap()).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        exporter.export(&snapshot).unwrap();
+        drop(exporter);
+
+        let received = handle.join().unwrap();
+        assert!(received.starts_with("hotmic,metric=requests count=42i "));
+    }
+
+    #[test]
+    fn test_influx_escaping() {
+        assert_eq!(escape(&"a,b=c d\\e", &[',', '=', ' ']), "a\\,b\\=c\\ d\\\\e");
+        assert_eq!(escape(&"multi\nline", &[' ']), "multi\\ line");
+        assert_eq!(integer(u64::MAX), format!("{}i", i64::MAX));
+    }
+}
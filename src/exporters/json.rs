@@ -0,0 +1,167 @@
+//! JSON rendering, available with the `serde` feature.
+//!
+//! Snapshots are rendered as an object with a `metrics` array, and an `internal` array for metrics
+//! about hotmic itself.  Every metric holds its `name`, as the metric key is displayed, its
+//! `labels` as an object, and its `type`:
+//! `counter` and `gauge` metrics have a single `value`, while `timing` and `distribution` metrics
+//! have the `count`, `sum`, `min` and `max` of their underlying histogram, along with their
+//! `percentiles` as an object keyed by percentile label.  Timings are in nanoseconds.
+//!
+//! ```json
+//! {"metrics":[{"name":"latency","labels":{},"type":"timing","count":1,"sum":100,"min":100,"max":100,"percentiles":{"p50":100}}],"internal":[]}
+//! ```
+use std::fmt::Display;
+use std::hash::Hash;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json;
+use data::{Labels, Snapshot, Summary, TypedMeasurement, Unscaled};
+
+#[derive(Serialize)]
+struct JsonSnapshot<'a> {
+    metrics: Vec<JsonMetric<'a>>,
+    internal: Vec<JsonMetric<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonMetric<'a> {
+    name: String,
+    labels: &'a Labels,
+    #[serde(flatten)]
+    value: JsonValue<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonValue<'a> {
+    Counter { value: i64 },
+    Gauge { value: i64 },
+    #[serde(rename = "gauge")]
+    FloatGauge { value: f64 },
+    Timing(JsonSummary<'a>),
+    Distribution(JsonSummary<'a>),
+}
+
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    count: u64,
+    sum: Unscaled,
+    min: Unscaled,
+    max: Unscaled,
+    percentiles: JsonPercentiles<'a>,
+}
+
+/// Percentiles, serialized as an object in their configured order.
+struct JsonPercentiles<'a>(&'a Summary);
+
+impl<'a> Serialize for JsonPercentiles<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let percentiles = self.0.percentiles();
+        let mut map = serializer.serialize_map(Some(percentiles.len()))?;
+        for (percentile, value) in percentiles {
+            map.serialize_entry(&percentile.0, &self.0.unscale(*value))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Unscaled {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Unscaled::Exact(value) => serializer.serialize_u64(value),
+            Unscaled::Float(value) => serializer.serialize_f64(value),
+        }
+    }
+}
+
+/// Renders a `Snapshot` as JSON.
+pub fn render<T: Send + Eq + Hash + Display + Clone>(snapshot: &Snapshot<T>) -> String {
+    let json = JsonSnapshot {
+        metrics: metrics(snapshot.measurements()),
+        internal: metrics(snapshot.internal_measurements()),
+    };
+
+    serde_json::to_string(&json).expect("snapshot should always be serializable")
+}
+
+fn metrics<T: Display>(measurements: &[TypedMeasurement<T>]) -> Vec<JsonMetric<'_>> {
+    measurements
+        .iter()
+        .map(|measurement| {
+            let key = measurement.key();
+            let value = match *measurement {
+                TypedMeasurement::Count(_, value) => JsonValue::Counter { value },
+                TypedMeasurement::Gauge(_, value) => JsonValue::Gauge { value },
+                TypedMeasurement::FloatGauge(_, value) => JsonValue::FloatGauge { value },
+                TypedMeasurement::TimingPercentile(_, ref summary) => JsonValue::Timing(summary_json(summary)),
+                TypedMeasurement::ValuePercentile(_, ref summary) => JsonValue::Distribution(summary_json(summary)),
+            };
+
+            JsonMetric {
+                name: key.name().to_string(),
+                labels: key.labels(),
+                value,
+            }
+        })
+        .collect()
+}
+
+fn summary_json(summary: &Summary) -> JsonSummary<'_> {
+    JsonSummary {
+        count: summary.count(),
+        sum: summary.unscale(summary.sum()),
+        min: summary.unscale(summary.min()),
+        max: summary.unscale(summary.max()),
+        percentiles: JsonPercentiles(summary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use data::{Key, Labels, Percentile, Snapshot};
+    use hdrhistogram::Histogram;
+
+    #[test]
+    fn test_json_render() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+        let percentiles = vec![Percentile("p99".to_owned(), 99.0), Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), Labels::new().with("endpoint", "/a")), 42);
+        snapshot.set_value("connections".to_owned(), 7);
+        snapshot.set_timing_percentiles("latency".to_owned(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles("size".to_owned(), h, &percentiles);
+        snapshot.set_internal_count("hotmic_dropped_samples", 0);
+
+        assert_eq!(render(&snapshot), concat!(
+            r#"{"metrics":["#,
+            r#"{"name":"requests","labels":{"endpoint":"/a"},"type":"counter","value":42},"#,
+            r#"{"name":"connections","labels":{},"type":"gauge","value":7},"#,
+            r#"{"name":"latency","labels":{},"type":"timing","count":1,"sum":100,"min":100,"max":100,"#,
+            r#""percentiles":{"p99":100,"p50":100}},"#,
+            r#"{"name":"size","labels":{},"type":"distribution","count":1,"sum":100,"min":100,"max":100,"#,
+            r#""percentiles":{"p99":100,"p50":100}}],"#,
+            r#""internal":[{"name":"hotmic_dropped_samples","labels":{},"type":"counter","value":0}]}"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_scaled_values() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(250);
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_float_value("load".to_owned(), -0.5);
+        snapshot.set_scaled_value_percentiles("ratio".to_owned(), h, &percentiles, 1000.0);
+
+        assert_eq!(render(&snapshot), concat!(
+            r#"{"metrics":["#,
+            r#"{"name":"load","labels":{},"type":"gauge","value":-0.5},"#,
+            r#"{"name":"ratio","labels":{},"type":"distribution","count":1,"sum":0.25,"min":0.25,"max":0.25,"#,
+            r#""percentiles":{"p50":0.25}}],"#,
+            r#""internal":[]}"#,
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! Exporters for rendering snapshots into formats understood by external systems.
+//!
+//! The push exporters -- StatsD, Graphite and InfluxDB -- can export on a dedicated thread, via
+//! their `run` method, which takes a snapshot every interval and runs until the receiver is gone.
+//! To export without a dedicated thread, register the exporter as an observer via
+//! `Receiver::add_observer` instead.  Either way, errors from individual exports are logged and
+//! otherwise ignored.
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use control::Controller;
+use data::Snapshot;
+use error::Error;
+
+pub mod graphite;
+pub mod influx;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod prometheus;
+pub mod statsd;
+
+/// Takes a snapshot every `interval`, handing it to `export`, until the receiver is gone.
+///
+/// Errors from individual snapshots, or exports, are logged and otherwise ignored.
+pub(crate) fn push_every<T, F>(name: &str, controller: &Controller<T>, interval: Duration, mut export: F)
+    where F: FnMut(&Snapshot<T>) -> io::Result<()>
+{
+    loop {
+        thread::sleep(interval);
+        match controller.get_snapshot() {
+            Ok(snapshot) => log_export(name, export(&snapshot)),
+            Err(Error::ReceiverGone) => return,
+            Err(e) => debug!("failed to get snapshot for {}: {}", name, e),
+        }
+    }
+}
+
+/// Logs the failure of an export, if any, to the named destination.
+///
+/// Shared by `push_every` and the `Observer` implementations of the push exporters.
+pub(crate) fn log_export(name: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        debug!("failed to export snapshot to {}: {}", name, e);
+    }
+}
+
+/// A TCP connection that is established on first use, and re-established after any error.
+pub(crate) struct Connection {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+}
+
+impl Connection {
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Connection> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+
+        Ok(Connection { addr, stream: None })
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, Duration::from_secs(5))?;
+            stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+            self.stream = Some(stream);
+        }
+
+        let result = match self.stream {
+            Some(ref mut stream) => stream.write_all(buf).and_then(|_| stream.flush()),
+            None => Ok(()),
+        };
+
+        // Whatever went wrong, start over with a fresh connection next time.
+        if result.is_err() {
+            self.stream = None;
+        }
+
+        result
+    }
+}
+
+/// Gets the time elapsed since the Unix epoch.
+pub(crate) fn since_epoch() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::new(0, 0))
+}
@@ -0,0 +1,308 @@
+//! Prometheus text exposition format.
+//!
+//! Counters are rendered as `counter` metrics with a `_total` suffix, and gauges are rendered as
+//! `gauge` metrics using the bare metric key.  Timing and value percentiles are rendered as
+//! `summary` metrics, with a `_nanoseconds` and `_distribution` suffix, respectively: each
+//! percentile is exposed with a `quantile` label.  A label of the metric's own named `quantile`
+//! is exposed as `exported_quantile` instead, the way Prometheus itself renames colliding labels.
+//!
+//! Summaries have no `_sum` or `_count` series: Prometheus expects those to be cumulative, but
+//! a snapshot only holds the count and sum of the histogram window, which go down as values fall
+//! out of it.
+//!
+//! Internal metrics, such as `hotmic_dropped_samples`, are rendered after the measurements, in
+//! the same way, if the receiver has instrumentation enabled.  An internal metric whose name a
+//! measurement already uses is left out, so that no metric is typed twice.
+use std::collections::HashSet;
+use std::fmt::{Display, Write};
+use std::hash::Hash;
+use data::{Labels, Percentile, Snapshot, Summary, TypedMeasurement};
+
+/// Renders a `Snapshot` in the Prometheus text exposition format.
+pub fn render<T: Send + Eq + Hash + Display + Clone>(snapshot: &Snapshot<T>) -> String {
+    let mut output = String::new();
+    let mut typed = HashSet::new();
+
+    write_measurements(&mut output, &mut typed, &HashSet::new(), snapshot.measurements());
+    let reserved = typed.clone();
+    write_measurements(&mut output, &mut typed, &reserved, snapshot.internal_measurements());
+
+    output
+}
+
+/// Writes out measurements, skipping any whose metric name is in `reserved`.
+fn write_measurements<T: Display>(
+    output: &mut String,
+    typed: &mut HashSet<String>,
+    reserved: &HashSet<String>,
+    measurements: &[TypedMeasurement<T>],
+) {
+    for measurement in measurements {
+        let name = metric_name(measurement);
+        if reserved.contains(&name) {
+            continue;
+        }
+
+        let labels = measurement.key().labels();
+        match *measurement {
+            TypedMeasurement::Count(_, value) => {
+                write_type(output, typed, &name, "counter");
+                let _ = writeln!(output, "{}{} {}", name, render_labels(labels, None), value);
+            },
+            TypedMeasurement::Gauge(_, value) => {
+                write_type(output, typed, &name, "gauge");
+                let _ = writeln!(output, "{}{} {}", name, render_labels(labels, None), value);
+            },
+            TypedMeasurement::FloatGauge(_, value) => {
+                write_type(output, typed, &name, "gauge");
+                let _ = writeln!(output, "{}{} {}", name, render_labels(labels, None), float(value));
+            },
+            TypedMeasurement::TimingPercentile(_, ref summary)
+            | TypedMeasurement::ValuePercentile(_, ref summary) => {
+                write_summary(output, typed, &name, labels, summary);
+            },
+        }
+    }
+}
+
+/// Gets the Prometheus metric name of a measurement, suffixed according to its kind.
+fn metric_name<T: Display>(measurement: &TypedMeasurement<T>) -> String {
+    let name = sanitize(measurement.key().name());
+    match *measurement {
+        TypedMeasurement::Count(..) => format!("{}_total", name),
+        TypedMeasurement::Gauge(..) | TypedMeasurement::FloatGauge(..) => name,
+        TypedMeasurement::TimingPercentile(..) => format!("{}_nanoseconds", name),
+        TypedMeasurement::ValuePercentile(..) => format!("{}_distribution", name),
+    }
+}
+
+fn write_type(output: &mut String, typed: &mut HashSet<String>, name: &str, kind: &str) {
+    // Every series of a metric shares a single type line.
+    if typed.insert(name.to_owned()) {
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    }
+}
+
+fn write_summary(output: &mut String, typed: &mut HashSet<String>, name: &str, labels: &Labels, summary: &Summary) {
+    write_type(output, typed, name, "summary");
+    for (percentile, value) in summary.percentiles() {
+        let rendered = render_labels(labels, Some(("quantile", &quantile(percentile))));
+        let _ = writeln!(output, "{}{} {}", name, rendered, summary.unscale(*value));
+    }
+}
+
+/// Renders a label set, with an optional extra label appended, as `{name="value",...}`.
+///
+/// Label names are sanitized, and label values are escaped, per the exposition format.  A label
+/// with the same name as the extra label is renamed with an `exported_` prefix.
+fn render_labels(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    if labels.is_empty() && extra.is_none() {
+        return String::new();
+    }
+
+    let rendered = labels
+        .iter()
+        .map(|(name, value)| {
+            let name = sanitize(&name);
+            match extra {
+                Some((extra, _)) if name == extra => (format!("exported_{}", name), value),
+                _ => (name, value),
+            }
+        })
+        .chain(extra.map(|(name, value)| (name.to_owned(), value)))
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", rendered.join(","))
+}
+
+/// Renders a floating-point value, spelling out NaN and infinities the way Prometheus expects.
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Converts a percentile, from 0 to 100, into a quantile, from 0 to 1.
+fn quantile(percentile: &Percentile) -> String {
+    let formatted = format!("{:.6}", percentile.1 / 100.0);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    if trimmed.is_empty() {
+        "0".to_owned()
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Coerces a metric key, or label name, into a valid Prometheus name.
+///
+/// Any character outside of `[a-zA-Z0-9_:]` is replaced with an underscore, and names that would
+/// otherwise start with a digit are prefixed with an underscore.
+fn sanitize<T: Display>(key: &T) -> String {
+    let mut name = String::new();
+    for (i, c) in key.to_string().chars().enumerate() {
+        if i == 0 && c.is_ascii_digit() {
+            name.push('_');
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+            name.push(c);
+        } else {
+            name.push('_');
+        }
+    }
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{float, render, quantile, sanitize};
+    use data::{Key, Labels, Percentile, Snapshot};
+    use hdrhistogram::Histogram;
+
+    #[test]
+    fn test_prometheus_counters_and_gauges() {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        snapshot.set_value("connections".to_owned(), 7);
+
+        let output = render(&snapshot);
+        assert!(output.contains("# TYPE requests_total counter\nrequests_total 42\n"));
+        assert!(output.contains("# TYPE connections gauge\nconnections 7\n"));
+    }
+
+    #[test]
+    fn test_prometheus_internal_metrics() {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        snapshot.set_internal_count("hotmic_dropped_samples", 3);
+
+        let output = render(&snapshot);
+        assert!(output.ends_with("# TYPE hotmic_dropped_samples_total counter\nhotmic_dropped_samples_total 3\n"));
+    }
+
+    #[test]
+    fn test_prometheus_internal_metric_collision() {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("hotmic.dropped-samples".to_owned(), 42);
+        snapshot.set_internal_count("hotmic_dropped_samples", 3);
+        snapshot.set_internal_count("hotmic_evicted_keys", 1);
+
+        let output = render(&snapshot);
+        assert_eq!(output.matches("# TYPE hotmic_dropped_samples_total counter\n").count(), 1);
+        assert!(output.contains("hotmic_dropped_samples_total 42\n"));
+        assert!(!output.contains("hotmic_dropped_samples_total 3\n"));
+        assert!(output.contains("hotmic_evicted_keys_total 1\n"));
+    }
+
+    #[test]
+    fn test_prometheus_summaries() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+
+        let percentiles = vec![
+            Percentile("p50".to_owned(), 50.0),
+            Percentile("p999".to_owned(), 99.9),
+            Percentile("max".to_owned(), 100.0),
+        ];
+
+        let mut snapshot = Snapshot::new();
+        snapshot.set_timing_percentiles("latency".to_owned(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles("size".to_owned(), h, &percentiles);
+
+        let output = render(&snapshot);
+        assert!(output.contains("# TYPE latency_nanoseconds summary\n"));
+        assert!(output.contains("latency_nanoseconds{quantile=\"0.5\"} 100\n"));
+        assert!(output.contains("latency_nanoseconds{quantile=\"0.999\"} 100\n"));
+        assert!(output.contains("latency_nanoseconds{quantile=\"1\"} 100\n"));
+        assert!(output.contains("# TYPE size_distribution summary\n"));
+        assert!(!output.contains("_sum"));
+        assert!(!output.contains("_count"));
+        assert!(output.contains("size_distribution{quantile=\"0.5\"} 100\n"));
+        assert!(!output.contains("_ns_"));
+    }
+
+    #[test]
+    fn test_prometheus_scaled_values() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(250);
+
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+        let mut snapshot = Snapshot::new();
+        snapshot.set_float_value("load".to_owned(), -0.5);
+        snapshot.set_scaled_value_percentiles("ratio".to_owned(), h, &percentiles, 1000.0);
+
+        let output = render(&snapshot);
+        assert!(output.contains("# TYPE load gauge\nload -0.5\n"));
+        assert!(output.contains("ratio_distribution{quantile=\"0.5\"} 0.25\n"));
+    }
+
+    #[test]
+    fn test_prometheus_labels() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+
+        let a = Labels::new().with("endpoint", "/a").with("status-code", "200");
+        let b = Labels::new().with("endpoint", "say \"hi\"");
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), a.clone()), 1);
+        snapshot.set_count(Key::new("requests".to_owned(), b), 2);
+        snapshot.set_timing_percentiles(Key::new("latency".to_owned(), a), h, &percentiles);
+
+        let output = render(&snapshot);
+        assert_eq!(output.matches("# TYPE requests_total counter\n").count(), 1);
+        assert!(output.contains("requests_total{endpoint=\"/a\",status_code=\"200\"} 1\n"));
+        assert!(output.contains("requests_total{endpoint=\"say \\\"hi\\\"\"} 2\n"));
+        assert!(output.contains("latency_nanoseconds{endpoint=\"/a\",status_code=\"200\",quantile=\"0.5\"} 100\n"));
+    }
+
+    #[test]
+    fn test_prometheus_quantile_label() {
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(100);
+
+        let labels = Labels::new().with("quantile", "high");
+        let percentiles = vec![Percentile("p50".to_owned(), 50.0)];
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), labels.clone()), 1);
+        snapshot.set_timing_percentiles(Key::new("latency".to_owned(), labels), h, &percentiles);
+
+        let output = render(&snapshot);
+        assert!(output.contains("requests_total{quantile=\"high\"} 1\n"));
+        assert!(output.contains("latency_nanoseconds{exported_quantile=\"high\",quantile=\"0.5\"} 100\n"));
+    }
+
+    #[test]
+    fn test_prometheus_float() {
+        assert_eq!(float(0.25), "0.25");
+        assert_eq!(float(f64::NAN), "NaN");
+        assert_eq!(float(f64::INFINITY), "+Inf");
+        assert_eq!(float(f64::NEG_INFINITY), "-Inf");
+    }
+
+    #[test]
+    fn test_prometheus_quantile() {
+        assert_eq!(quantile(&Percentile("min".to_owned(), 0.0)), "0");
+        assert_eq!(quantile(&Percentile("p90".to_owned(), 90.0)), "0.9");
+        assert_eq!(quantile(&Percentile("p9999".to_owned(), 99.99)), "0.9999");
+        assert_eq!(quantile(&Percentile("max".to_owned(), 100.0)), "1");
+    }
+
+    #[test]
+    fn test_prometheus_sanitize() {
+        assert_eq!(sanitize(&"ok"), "ok");
+        assert_eq!(sanitize(&"http.requests-total"), "http_requests_total");
+        assert_eq!(sanitize(&"5xx"), "_5xx");
+    }
+}
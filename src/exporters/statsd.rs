@@ -0,0 +1,389 @@
+//! StatsD, and DogStatsD, push exporter over UDP.
+//!
+//! Counters are sent as `|c` metrics: as snapshots hold running totals, each export sends the
+//! change since the previous export, which is negative if a counter went down.  A series that
+//! disappears from a snapshot, such as when its facet is removed or its key evicted, is
+//! forgotten, so if it comes back, its whole total is sent as the change.
+//!
+//! Gauges are sent as `|g` metrics, with negative values preceded by a reset to zero, as StatsD
+//! reads a signed gauge value as an adjustment.  Timing percentiles, in milliseconds, and value
+//! percentiles are sent as `|g` metrics too, with the percentile label -- `p99`, `max`, and so on
+//! -- appended to the metric name: they are already aggregated, and sending them as `|ms` timers
+//! would have the agent aggregate them all over again.
+//!
+//! With DogStatsD enabled, labels are sent as tags.  Otherwise, as plain StatsD has no notion of
+//! tags, each label is appended to the metric name as `.{name}.{value}`.
+use std::fmt::{Display, Write};
+use std::hash::Hash;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use fnv::FnvHashMap;
+use control::Controller;
+use observer::Observer;
+use data::{Labels, Snapshot, TypedMeasurement};
+use super::{log_export, push_every};
+
+/// Maximum payload of a single datagram, chosen to fit in a typical Ethernet MTU.
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Pushes snapshots to a StatsD agent over UDP.
+pub struct Exporter {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: Option<String>,
+    dogstatsd: bool,
+    counts: FnvHashMap<String, i64>,
+}
+
+impl Exporter {
+    /// Creates a new `Exporter` that sends to the given address.
+    ///
+    /// The local socket is bound to an ephemeral port on the unspecified address.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Exporter> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+
+        Ok(Exporter {
+            socket,
+            addr,
+            prefix: None,
+            dogstatsd: false,
+            counts: FnvHashMap::default(),
+        })
+    }
+
+    /// Sets a prefix for all metric names.
+    ///
+    /// The prefix is separated from metric names with a dot.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Sets whether labels are sent as DogStatsD tags.
+    ///
+    /// Defaults to `false`.
+    pub fn dogstatsd(mut self, enabled: bool) -> Self {
+        self.dogstatsd = enabled;
+        self
+    }
+
+    /// Renders a snapshot into StatsD lines.
+    ///
+    /// Counter values are rendered relative to the previously rendered snapshot, which is then
+    /// replaced as the point of reference for the next one.
+    pub fn render<T: Send + Eq + Hash + Display + Clone>(&mut self, snapshot: &Snapshot<T>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut counts = FnvHashMap::default();
+        self.render_measurements(&mut lines, &mut counts, snapshot.measurements());
+        self.render_measurements(&mut lines, &mut counts, snapshot.internal_measurements());
+
+        // Only keep the series in this snapshot as points of reference.
+        self.counts = counts;
+        lines
+    }
+
+    /// Renders and sends a snapshot.
+    pub fn export<T: Send + Eq + Hash + Display + Clone>(&mut self, snapshot: &Snapshot<T>) -> io::Result<()> {
+        let lines = self.render(snapshot);
+
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+                self.socket.send_to(packet.as_bytes(), self.addr)?;
+                packet.clear();
+            }
+
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+
+        if !packet.is_empty() {
+            self.socket.send_to(packet.as_bytes(), self.addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Exports a snapshot, retrieved via `controller`, every `interval`.
+    ///
+    /// See the [module documentation](../index.html) for how pushing behaves.
+    pub fn run<T: Send + Eq + Hash + Display + Clone>(&mut self, controller: &Controller<T>, interval: Duration) {
+        push_every("statsd", controller, interval, |snapshot| self.export(snapshot));
+    }
+
+    fn render_measurements<T: Display>(
+        &self,
+        lines: &mut Vec<String>,
+        counts: &mut FnvHashMap<String, i64>,
+        measurements: &[TypedMeasurement<T>],
+    ) {
+        for measurement in measurements {
+            let key = measurement.key();
+            let name = self.name(key.name(), key.labels());
+            let tags = self.tags(key.labels());
+            match *measurement {
+                TypedMeasurement::Count(_, value) => {
+                    let series = format!("{}{}", name, tags);
+                    let previous = self.counts.get(&series).cloned().unwrap_or(0);
+                    counts.insert(series, value);
+                    lines.push(format!("{}:{}|c{}", name, value.wrapping_sub(previous), tags));
+                },
+                TypedMeasurement::Gauge(_, value) => {
+                    // A signed gauge value is read as an adjustment, so negative values have to be
+                    // set by first resetting the gauge to zero.
+                    if value < 0 {
+                        lines.push(format!("{}:0|g{}", name, tags));
+                    }
+                    lines.push(format!("{}:{}|g{}", name, value, tags));
+                },
+                TypedMeasurement::FloatGauge(_, value) => {
+                    if value < 0.0 {
+                        lines.push(format!("{}:0|g{}", name, tags));
+                    }
+                    lines.push(format!("{}:{}|g{}", name, value, tags));
+                },
+                TypedMeasurement::TimingPercentile(_, ref summary) => {
+                    for (percentile, value) in summary.percentiles() {
+                        lines.push(format!("{}.{}:{}|g{}", name, sanitize(&percentile.0), milliseconds(*value), tags));
+                    }
+                },
+                TypedMeasurement::ValuePercentile(_, ref summary) => {
+                    for (percentile, value) in summary.percentiles() {
+                        lines.push(format!("{}.{}:{}|g{}", name, sanitize(&percentile.0), summary.unscale(*value), tags));
+                    }
+                },
+            }
+        }
+    }
+
+    fn name<T: Display>(&self, key: &T, labels: &Labels) -> String {
+        let mut name = String::new();
+        if let Some(ref prefix) = self.prefix {
+            name.push_str(&sanitize(prefix));
+            name.push('.');
+        }
+        name.push_str(&sanitize(key));
+
+        if !self.dogstatsd {
+            for (label, value) in labels.iter() {
+                let _ = write!(name, ".{}.{}", sanitize(&label).replace('.', "_"), sanitize(&value).replace('.', "_"));
+            }
+        }
+
+        name
+    }
+
+    fn tags(&self, labels: &Labels) -> String {
+        if !self.dogstatsd || labels.is_empty() {
+            return String::new();
+        }
+
+        let tags = labels
+            .iter()
+            .map(|(label, value)| format!("{}:{}", sanitize(&label), sanitize(&value)))
+            .collect::<Vec<_>>();
+
+        format!("|#{}", tags.join(","))
+    }
+}
+
+impl<T: Send + Eq + Hash + Display + Clone> Observer<T> for Exporter {
+    fn observe(&mut self, snapshot: &Snapshot<T>) {
+        log_export("statsd", self.export(snapshot));
+    }
+}
+
+/// Converts nanoseconds to milliseconds, without any trailing zeros.
+fn milliseconds(nanos: u64) -> String {
+    let formatted = format!("{}.{:06}", nanos / 1_000_000, nanos % 1_000_000);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+/// Replaces any character with special meaning in the StatsD protocol with an underscore.
+fn sanitize<T: Display>(s: &T) -> String {
+    s.to_string()
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{milliseconds, sanitize, Exporter};
+    use data::{Facet, Key, Labels, Percentile, Sample, Snapshot};
+    use hdrhistogram::Histogram;
+    use receiver::Receiver;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    fn local_agent() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    fn receive(socket: &UdpSocket) -> String {
+        let mut buf = [0; 2048];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_statsd_export() {
+        let agent = local_agent();
+        let mut exporter = Exporter::new(agent.local_addr().unwrap()).unwrap().prefix("app");
+
+        let mut h = Histogram::<u64>::new_with_bounds(1, u64::MAX, 3).unwrap();
+        h.saturating_record(1_500);
+        let percentiles = vec![Percentile("p99".to_owned(), 99.0)];
+
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 42);
+        snapshot.set_value("connections".to_owned(), 7);
+        snapshot.set_timing_percentiles("latency".to_owned(), h.clone(), &percentiles);
+        snapshot.set_value_percentiles("size".to_owned(), h, &percentiles);
+        exporter.export(&snapshot).unwrap();
+
+        let packet = receive(&agent);
+        let lines = packet.lines().collect::<Vec<_>>();
+        assert_eq!(lines, vec![
+            "app.requests:42|c",
+            "app.connections:7|g",
+            "app.latency.p99:0.0015|g",
+            "app.size.p99:1500|g",
+        ]);
+
+        // Counters are sent as the change since the last export.
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("requests".to_owned(), 50);
+        exporter.export(&snapshot).unwrap();
+        assert_eq!(receive(&agent), "app.requests:8|c");
+    }
+
+    #[test]
+    fn test_statsd_counter_deltas() {
+        let agent = local_agent();
+        let mut exporter = Exporter::new(agent.local_addr().unwrap()).unwrap();
+
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("balance".to_owned(), 10);
+        snapshot.set_count("requests".to_owned(), 5);
+        assert_eq!(exporter.render(&snapshot), vec!["balance:10|c", "requests:5|c"]);
+
+        // Counters can go down, too.
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("balance".to_owned(), 8);
+        assert_eq!(exporter.render(&snapshot), vec!["balance:-2|c"]);
+
+        // A series missing from the previous snapshot starts over from zero.
+        let mut snapshot = Snapshot::new();
+        snapshot.set_count("balance".to_owned(), 8);
+        snapshot.set_count("requests".to_owned(), 2);
+        assert_eq!(exporter.render(&snapshot), vec!["balance:0|c", "requests:2|c"]);
+    }
+
+    #[test]
+    fn test_statsd_negative_gauges() {
+        let agent = local_agent();
+        let mut exporter = Exporter::new(agent.local_addr().unwrap()).unwrap();
+
+        // A signed gauge value would be read as an adjustment, so the gauge is reset first.
+        let mut snapshot = Snapshot::new();
+        snapshot.set_value("balance".to_owned(), -5);
+        exporter.export(&snapshot).unwrap();
+        assert_eq!(receive(&agent), "balance:0|g\nbalance:-5|g");
+    }
+
+    #[test]
+    fn test_statsd_labels() {
+        let agent = local_agent();
+        let labels = Labels::new().with("endpoint", "/a").with("status", "200");
+
+        let mut snapshot = Snapshot::<String>::new();
+        snapshot.set_count(Key::new("requests".to_owned(), labels.clone()), 3);
+        snapshot.set_value(Key::new("connections".to_owned(), labels), 7);
+
+        let mut plain = Exporter::new(agent.local_addr().unwrap()).unwrap();
+        assert_eq!(plain.render(&snapshot), vec![
+            "requests.endpoint./a.status.200:3|c",
+            "connections.endpoint./a.status.200:7|g",
+        ]);
+
+        let mut tagged = Exporter::new(agent.local_addr().unwrap()).unwrap().dogstatsd(true);
+        assert_eq!(tagged.render(&snapshot), vec![
+            "requests:3|c|#endpoint:/a,status:200",
+            "connections:7|g|#endpoint:/a,status:200",
+        ]);
+    }
+
+    #[test]
+    fn test_statsd_split_packets() {
+        let agent = local_agent();
+        let mut exporter = Exporter::new(agent.local_addr().unwrap()).unwrap();
+
+        let mut snapshot = Snapshot::new();
+        for i in 0..200 {
+            snapshot.set_value(format!("gauge_{}", i), i);
+        }
+        exporter.export(&snapshot).unwrap();
+
+        let mut received = 0;
+        while received < 200 {
+            let packet = receive(&agent);
+            assert!(packet.len() <= super::MAX_PACKET_SIZE);
+            received += packet.lines().count();
+        }
+        assert_eq!(received, 200);
+    }
+
+    #[test]
+    fn test_statsd_run() {
+        let agent = local_agent();
+
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        receiver.add_facet(Facet::Count("requests".to_owned()));
+        receiver.get_sink().send(Sample::Count("requests".to_owned(), 3)).unwrap();
+
+        let controller = receiver.get_controller();
+        let mut exporter = Exporter::new(agent.local_addr().unwrap()).unwrap();
+        let exporter_controller = controller.clone();
+        let handle = thread::spawn(move || exporter.run(&exporter_controller, Duration::from_millis(10)));
+        let receiver_handle = thread::spawn(move || receiver.run());
+
+        // The first export may race with the sample being processed, but the total has to show up.
+        let mut packet = receive(&agent);
+        for _ in 0..50 {
+            if packet == "requests:3|c" {
+                break;
+            }
+            packet = receive(&agent);
+        }
+        assert_eq!(packet, "requests:3|c");
+
+        // Once the receiver is gone, the exporter stops.
+        controller.shutdown().unwrap();
+        receiver_handle.join().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_statsd_formatting() {
+        assert_eq!(milliseconds(1_500_000), "1.5");
+        assert_eq!(milliseconds(2_000_000), "2");
+        assert_eq!(milliseconds(1_234), "0.001234");
+        assert_eq!(milliseconds(0), "0");
+        assert_eq!(sanitize(&"a:b|c@d#e,f g"), "a_b_c_d_e_f_g");
+    }
+}
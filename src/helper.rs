@@ -1,13 +1,10 @@
-use std::io::{Error, ErrorKind};
 use std::time::Duration;
 
-pub fn io_error(reason: &str) -> Error {
-    Error::new(ErrorKind::Other, reason)
-}
-
-/// Converts a duration to nanoseconds.
+/// Converts a duration to nanoseconds, saturating at `u64::MAX`.
 pub fn duration_as_nanos(d: Duration) -> u64 {
-    (d.as_secs() * 1_000_000_000) + d.subsec_nanos() as u64
+    d.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(d.subsec_nanos()))
 }
 
 #[cfg(test)]
@@ -22,5 +19,6 @@ mod tests {
 
         assert_eq!(duration_as_nanos(d1), 3_000_000_000);
         assert_eq!(duration_as_nanos(d2), 500_000_000);
+        assert_eq!(duration_as_nanos(Duration::from_secs(u64::MAX)), u64::MAX);
     }
 }
@@ -0,0 +1,288 @@
+//! A minimal HTTP endpoint for scraping snapshots.
+//!
+//! `Server` binds a local TCP address and answers `GET /metrics` by requesting a snapshot through
+//! a `Controller`, so the `Receiver` itself does not need to know anything about HTTP.  Each
+//! connection is handled on a thread of its own, so a slow client can't hold up the others.
+//!
+//! Requires the `http` feature.
+use std::fmt::Display;
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use control::Controller;
+use data::{Snapshot, TypedMeasurement};
+use exporters::prometheus;
+#[cfg(feature = "serde")]
+use exporters::json;
+
+const METRICS_PATH: &str = "/metrics";
+const MAX_HEADER_LINES: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Output format for served snapshots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The Prometheus text exposition format.
+    Prometheus,
+
+    /// The flattened snapshot keys, and internal metrics, one `key value` pair per line, sorted by
+    /// key.
+    Text,
+
+    /// JSON, with percentiles nested under each metric.  Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match *self {
+            Format::Prometheus => "text/plain; version=0.0.4",
+            Format::Text => "text/plain",
+            #[cfg(feature = "serde")]
+            Format::Json => "application/json",
+        }
+    }
+
+    fn render<T: Send + Eq + Hash + Display + Clone>(&self, snapshot: &Snapshot<T>) -> String {
+        match *self {
+            Format::Prometheus => prometheus::render(snapshot),
+            Format::Text => render_text(snapshot),
+            #[cfg(feature = "serde")]
+            Format::Json => json::render(snapshot),
+        }
+    }
+}
+
+/// HTTP server that serves snapshots on `GET /metrics`.
+pub struct Server<T> {
+    listener: TcpListener,
+    controller: Controller<T>,
+    format: Format,
+}
+
+impl<T: Send + Eq + Hash + Display + Clone + 'static> Server<T> {
+    /// Binds a new `Server` to the given address.
+    ///
+    /// Snapshots are retrieved via `controller`, and are served in the Prometheus format unless
+    /// changed via `format`.
+    pub fn bind<A: ToSocketAddrs>(addr: A, controller: Controller<T>) -> io::Result<Server<T>> {
+        let listener = TcpListener::bind(addr)?;
+
+        Ok(Server {
+            listener,
+            controller,
+            format: Format::Prometheus,
+        })
+    }
+
+    /// Sets the format snapshots are served in.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Gets the local address this server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests endlessly.
+    ///
+    /// Every connection is handled on a new thread.  Clients get a second to send their request,
+    /// and to read the response.  Errors from individual connections are logged and otherwise
+    /// ignored.
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("failed to accept metrics request: {}", e);
+                    continue;
+                },
+            };
+
+            let controller = self.controller.clone();
+            let format = self.format;
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &controller, format) {
+                    debug!("failed to handle metrics request: {}", e);
+                }
+            });
+        }
+    }
+}
+
+fn handle<T: Send + Eq + Hash + Display + Clone>(stream: TcpStream, controller: &Controller<T>, format: Format) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain the headers: we don't use them, but the client expects them to be read.
+    for _ in 0..MAX_HEADER_LINES {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    let mut stream = reader.into_inner();
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", "method not allowed\n");
+    }
+
+    if path != METRICS_PATH {
+        return respond(&mut stream, "404 Not Found", "text/plain", "not found\n");
+    }
+
+    match controller.get_snapshot() {
+        Ok(snapshot) => {
+            let body = format.render(&snapshot);
+            respond(&mut stream, "200 OK", format.content_type(), &body)
+        },
+        Err(e) => {
+            let body = format!("failed to get snapshot: {}\n", e);
+            respond(&mut stream, "503 Service Unavailable", "text/plain", &body)
+        },
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn render_text<T: Send + Eq + Hash + Display + Clone>(snapshot: &Snapshot<T>) -> String {
+    let mut lines = Vec::new();
+    for (key, value) in &snapshot.signed_data {
+        lines.push(format!("{} {}\n", key, value));
+    }
+    for (key, value) in &snapshot.unsigned_data {
+        lines.push(format!("{} {}\n", key, value));
+    }
+    for measurement in snapshot.internal_measurements() {
+        if let TypedMeasurement::Count(ref key, value) = *measurement {
+            lines.push(format!("{} {}\n", key, value));
+        }
+    }
+    lines.sort();
+    lines.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Server};
+    use data::{Facet, Sample};
+    use receiver::Receiver;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn scrape_until(addr: SocketAddr, expected: &str) -> String {
+        let mut response = String::new();
+        for _ in 0..50 {
+            response = request(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            if response.contains(expected) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        response
+    }
+
+    fn spawn_server(format: Format) -> SocketAddr {
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        receiver.add_facet(Facet::Count("requests".to_owned()));
+
+        let mut sink = receiver.get_sink();
+        sink.send(Sample::Count("requests".to_owned(), 3)).unwrap();
+
+        let server = Server::bind("127.0.0.1:0", receiver.get_controller())
+            .unwrap()
+            .format(format);
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || receiver.run());
+        thread::spawn(move || server.run());
+
+        addr
+    }
+
+    #[test]
+    fn test_http_slow_client() {
+        let addr = spawn_server(Format::Prometheus);
+
+        // A client that never sends its request doesn't hold up the others.
+        let _idle = TcpStream::connect(addr).unwrap();
+        let response = scrape_until(addr, "requests_total 3");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_http_prometheus_format() {
+        let addr = spawn_server(Format::Prometheus);
+
+        let response = scrape_until(addr, "requests_total 3");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("# TYPE requests_total counter\nrequests_total 3\n"));
+    }
+
+    #[test]
+    fn test_http_text_format() {
+        let addr = spawn_server(Format::Text);
+
+        let response = scrape_until(addr, "requests_count 3");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nrequests_count 3\n"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_http_json_format() {
+        let addr = spawn_server(Format::Json);
+
+        let response = scrape_until(addr, r#""value":3"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.contains(r#"{"name":"requests","labels":{},"type":"counter","value":3}"#));
+    }
+
+    #[test]
+    fn test_http_unknown_path_and_method() {
+        let addr = spawn_server(Format::Prometheus);
+
+        let response = request(addr, "GET /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = request(addr, "POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -1,20 +1,38 @@
+#[macro_use]
 extern crate log;
 extern crate fnv;
 extern crate mio;
 extern crate crossbeam_channel;
 extern crate lazycell;
 extern crate hdrhistogram;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
 mod channel;
+mod clock;
 mod configuration;
 mod control;
 mod data;
+mod error;
+mod observer;
 mod receiver;
 mod sink;
 mod helper;
 
-pub use configuration::Configuration;
-pub use data::{Facet, Sample, Percentile, Snapshot};
-pub use sink::Sink;
+pub mod exporters;
+#[cfg(feature = "http")]
+pub mod http;
+
+pub use clock::{Clock, MockClock, SystemClock};
+pub use configuration::{AutoRegistration, BackpressurePolicy, CardinalityPolicy, Configuration, HistogramConfiguration};
+pub use data::{Delta, Facet, FacetKind, Key, Labels, MergedHistogram, Sample, Percentile, Snapshot, Summary, TypedMeasurement};
+pub use sink::{InFlightGuard, Sink, TimingGuard};
 pub use receiver::Receiver;
 pub use control::Controller;
+pub use error::Error;
+pub use observer::Observer;
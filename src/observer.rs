@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+use data::Snapshot;
+
+/// A consumer of snapshots, invoked by the `Receiver` on a fixed interval.
+///
+/// Observers are registered via `Receiver::add_observer`, and are called from within
+/// `Receiver::turn`, on the same thread that processes samples: they should hand off, or finish,
+/// their work quickly.
+///
+/// Any closure taking a `&Snapshot<T>` is an observer, as are the push exporters.
+pub trait Observer<T>: Send {
+    /// Observes a snapshot.
+    fn observe(&mut self, snapshot: &Snapshot<T>);
+}
+
+impl<T, F> Observer<T> for F
+    where F: FnMut(&Snapshot<T>) + Send
+{
+    fn observe(&mut self, snapshot: &Snapshot<T>) {
+        self(snapshot)
+    }
+}
+
+/// An observer, along with when it should next be invoked.
+pub(crate) struct Scheduled<T> {
+    observer: Box<dyn Observer<T>>,
+    interval: Duration,
+    next: Instant,
+}
+
+impl<T> Scheduled<T> {
+    pub fn new(observer: Box<dyn Observer<T>>, interval: Duration, now: Instant) -> Scheduled<T> {
+        Scheduled {
+            observer,
+            interval,
+            next: now + interval,
+        }
+    }
+
+    /// Whether or not the observer should be invoked at the given time.
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    /// Invokes the observer, and schedules its next invocation.
+    ///
+    /// Invocations stay on a fixed cadence, but any that were missed entirely -- because a turn
+    /// took far too long, say -- are skipped rather than run back to back.
+    pub fn observe(&mut self, snapshot: &Snapshot<T>, now: Instant) {
+        self.observer.observe(snapshot);
+        self.next += self.interval;
+        if self.next <= now {
+            self.next = now + self.interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduled;
+    use data::Snapshot;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_scheduled_cadence() {
+        let calls = Arc::new(Mutex::new(0));
+        let counted = calls.clone();
+        let observer = move |_: &Snapshot<String>| *counted.lock().unwrap() += 1;
+
+        let start = Instant::now();
+        let interval = Duration::from_millis(10);
+        let mut scheduled = Scheduled::new(Box::new(observer), interval, start);
+        assert!(!scheduled.is_due(start));
+        assert!(scheduled.is_due(start + interval));
+
+        // Running a little late doesn't push back the next invocation.
+        let snapshot = Snapshot::new();
+        scheduled.observe(&snapshot, start + Duration::from_millis(12));
+        assert!(!scheduled.is_due(start + Duration::from_millis(19)));
+        assert!(scheduled.is_due(start + Duration::from_millis(20)));
+
+        // Running very late skips the missed invocations entirely.
+        scheduled.observe(&snapshot, start + Duration::from_millis(55));
+        assert!(!scheduled.is_due(start + Duration::from_millis(60)));
+        assert!(scheduled.is_due(start + Duration::from_millis(65)));
+
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+}
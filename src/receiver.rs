@@ -1,72 +1,140 @@
 use crossbeam_channel;
 use mio::{Poll, Events, Ready, Token, PollOpt};
 use channel;
-use configuration::Configuration;
+use configuration::{BackpressurePolicy, Configuration, HistogramConfiguration};
 use control::{ControlMessage, Controller};
-use sink::Sink;
-use data::{Facet, Sample, Counter, Gauge, Histogram, Snapshot, Percentile, default_percentiles};
+use sink::{Sink, SinkStats};
+use observer::{Observer, Scheduled};
+use data::{Facet, Sample, Counter, Gauge, Histogram, Key, Labels, Level, Limiter, Registration, Snapshot, Summary, TypedMeasurement};
+use fnv::FnvHashMap;
 use std::hash::Hash;
 use std::fmt::Display;
 use std::time::{Instant, Duration};
 use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use helper::duration_as_nanos;
 
 const DATA: Token = Token(5);
 const CONTROL: Token = Token(15);
 
+/// How often upkeep runs, at most.
+///
+/// Rotating a histogram's window catches up on however much time has passed, so this only bounds
+/// how late a rotation can be, not whether it happens.
+const UPKEEP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The counter values seen by the last delta snapshot for a given subscriber.
+struct Subscription<T> {
+    taken: Instant,
+    counts: FnvHashMap<Key<T>, i64>,
+}
+
 /// Metrics receiver which aggregates and processes samples.
 pub struct Receiver<T> {
     conf: Configuration<T>,
 
     // Sample aggregation machinery.
     poll: Poll,
-    buffer_pool_tx: crossbeam_channel::Sender<Vec<Sample<T>>>,
-    buffer_pool_rx: crossbeam_channel::Receiver<Vec<Sample<T>>>,
-    data_tx: channel::Sender<Vec<Sample<T>>>,
-    data_rx: channel::Receiver<Vec<Sample<T>>>,
+    buffer_pool_tx: crossbeam_channel::Sender<Vec<(Sample<T>, Labels)>>,
+    buffer_pool_rx: crossbeam_channel::Receiver<Vec<(Sample<T>, Labels)>>,
+    data_tx: channel::Sender<Vec<(Sample<T>, Labels)>>,
+    data_rx: channel::Receiver<Vec<(Sample<T>, Labels)>>,
     control_tx: channel::Sender<ControlMessage<T>>,
     control_rx: channel::Receiver<ControlMessage<T>>,
     facets: HashSet<Facet<T>>,
+    known: HashSet<T>,
+    auto_registered: usize,
+    rejected: usize,
+    evicted: usize,
+    invalid: usize,
+    clamped: usize,
+    encoding_failures: usize,
+    stats: Arc<SinkStats>,
+    samples_processed: u64,
+    batches_processed: u64,
+    snapshot_duration: Duration,
+    observers: Vec<Scheduled<T>>,
+    subscriptions: FnvHashMap<String, Subscription<T>>,
 
     // Metric machinery.
     counter: Counter<T>,
     gauge: Gauge<T>,
     histogram: Histogram<T>,
-    percentiles: Vec<Percentile>,
+    started: Instant,
     last_upkeep: Instant,
+    upkeep_interval: Duration,
+    shutdown: bool,
 }
 
 impl<T: Send + Eq + Hash + Display + Clone> Receiver<T> {
     pub(crate) fn from_config(conf: Configuration<T>) -> Receiver<T> {
-        // Create our data, control, and buffer channels.
-        let (data_tx, data_rx) = channel::channel::<Vec<Sample<T>>>(conf.capacity);
+        // Create our data, control, and buffer channels.  If sinks are allowed to allocate their own
+        // buffers, there's no telling how many will be in flight, so nothing can be bounded.
+        let (data_tx, data_rx, buffer_pool_tx, buffer_pool_rx) = match conf.backpressure {
+            BackpressurePolicy::Grow => {
+                let (data_tx, data_rx) = channel::unbounded();
+                let (buffer_pool_tx, buffer_pool_rx) = crossbeam_channel::unbounded();
+                (data_tx, data_rx, buffer_pool_tx, buffer_pool_rx)
+            },
+            _ => {
+                let (data_tx, data_rx) = channel::channel(conf.capacity);
+                let (buffer_pool_tx, buffer_pool_rx) = crossbeam_channel::bounded(conf.capacity);
+                (data_tx, data_rx, buffer_pool_tx, buffer_pool_rx)
+            },
+        };
         let (control_tx, control_rx) = channel::channel::<ControlMessage<T>>(conf.capacity);
-        let (buffer_pool_tx, buffer_pool_rx) = crossbeam_channel::bounded::<Vec<Sample<T>>>(conf.capacity);
 
         // Pre-allocate our sample batch buffers and put them into the buffer channel.
         for _ in 0..conf.capacity {
-            let _ = buffer_pool_tx.send(Vec::with_capacity(conf.batch_size));
+            buffer_pool_tx.send(Vec::with_capacity(conf.batch_size));
         }
 
+        let policy = conf.cardinality_policy.clone();
+        let limiter = |limit| Limiter::new(limit, policy.clone()).with_series_limit(conf.series_limit);
+        let counter = Counter::new().with_limiter(limiter(conf.counter_limit));
+        let gauge = Gauge::new().with_limiter(limiter(conf.gauge_limit));
+        let histogram = Histogram::new(conf.histogram.clone())
+            .with_limiter(limiter(conf.histogram_limit))
+            .with_clock(conf.clock.clone());
+        let now = conf.clock.now();
+        let upkeep_interval = UPKEEP_INTERVAL.min(conf.histogram.granularity);
+
         // Configure our poller.
         let poll = Poll::new().unwrap();
         poll.register(&data_rx, DATA, Ready::readable(), PollOpt::level()).unwrap();
         poll.register(&control_rx, CONTROL, Ready::readable(), PollOpt::level()).unwrap();
 
         Receiver {
-            conf: conf,
-            poll: poll,
-            buffer_pool_rx: buffer_pool_rx,
-            buffer_pool_tx: buffer_pool_tx,
-            data_tx: data_tx,
-            data_rx: data_rx,
-            control_tx: control_tx,
-            control_rx: control_rx,
+            conf,
+            poll,
+            buffer_pool_rx,
+            buffer_pool_tx,
+            data_tx,
+            data_rx,
+            control_tx,
+            control_rx,
             facets: HashSet::new(),
-            counter: Counter::new(),
-            gauge: Gauge::new(),
-            histogram: Histogram::new(Duration::from_secs(10), Duration::from_secs(1)),
-            percentiles: default_percentiles(),
-            last_upkeep: Instant::now(),
+            known: HashSet::new(),
+            auto_registered: 0,
+            rejected: 0,
+            evicted: 0,
+            invalid: 0,
+            clamped: 0,
+            encoding_failures: 0,
+            stats: Arc::new(SinkStats::default()),
+            samples_processed: 0,
+            batches_processed: 0,
+            snapshot_duration: Duration::new(0, 0),
+            observers: Vec::new(),
+            subscriptions: FnvHashMap::default(),
+            counter,
+            gauge,
+            histogram,
+            started: now,
+            last_upkeep: now,
+            upkeep_interval,
+            shutdown: false,
         }
     }
 
@@ -82,29 +150,41 @@ impl<T: Send + Eq + Hash + Display + Clone> Receiver<T> {
             self.data_tx.clone(),
             self.control_tx.clone(),
             self.conf.batch_size,
+            self.conf.backpressure,
+            self.stats.clone(),
+            self.conf.clock.clone(),
         )
     }
 
     /// Creates a `Controller` bound to this receiver.
     pub fn get_controller(&self) -> Controller<T> {
-        Controller::new(self.control_tx.clone())
+        Controller::new(self.control_tx.clone(), self.conf.snapshot_timeout)
     }
 
     /// Run the receiver for a single turn.
     ///
     /// A single turn involves performing upkeep (adjusting histograms to make sure their windowing
-    /// is correct) and doing a single poll to see if any new data or control messages are
-    /// available.
+    /// is correct), invoking any observers that are due, and doing a single poll to see if any new
+    /// data or control messages are available.
     ///
     /// By default, the poll delay, or how long the call to `poll` will wait before timing out, is
     /// set at 100ms.  This is important as we want to ensure the `poll` eventually returns during
     /// periods of inactivity so it can be be recalled (when running via `run`) and perform
     /// continued upkeep.
     pub fn turn(&mut self) {
-        // Run upkeep before doing anything else.
-        let now = Instant::now();
-        if now >= self.last_upkeep + Duration::from_millis(250) {
+        // Run upkeep before doing anything else.  Histograms with a finer granularity, whether by
+        // default or for their own metric, get upkeep just as often as they need it.
+        let now = self.conf.clock.now();
+        if now >= self.last_upkeep + self.upkeep_interval {
             self.histogram.upkeep(now);
+            self.last_upkeep = now;
+        }
+
+        if self.observers.iter().any(|o| o.is_due(now)) {
+            let snapshot = self.get_snapshot();
+            for observer in self.observers.iter_mut().filter(|o| o.is_due(now)) {
+                observer.observe(&snapshot, now);
+            }
         }
 
         let mut events = Events::with_capacity(1024);
@@ -112,55 +192,50 @@ impl<T: Send + Eq + Hash + Display + Clone> Receiver<T> {
         for event in events.iter() {
             let token = event.token();
             if token == DATA {
-                if let Ok(mut results) = self.data_rx.recv() {
-                    for result in &results {
-                        self.counter.update(result);
-                        self.gauge.update(result);
-                        self.histogram.update(result);
-                    }
-                    results.clear();
-                    let _ = self.buffer_pool_tx.send(results);
+                if let Ok(results) = self.data_rx.recv() {
+                    self.process_batch(results);
                 }
             } else if token == CONTROL {
                 if let Ok(msg) = self.control_rx.recv() {
                     match msg {
-                        ControlMessage::AddFacet(facet) => self.add_facet(facet),
+                        ControlMessage::AddFacet(facet, conf) => {
+                            self.register_facet(facet, conf);
+                        },
                         ControlMessage::RemoveFacet(facet) => self.remove_facet(facet),
                         ControlMessage::Snapshot(tx) => {
-                            let mut snapshot = Snapshot::new();
-                            for facet in &self.facets {
-								match *facet {
-									Facet::Count(ref key) => {
-										snapshot.set_count(
-											key.clone(),
-											self.counter.value(key.clone())
-										);
-									},
-                                    Facet::Gauge(ref key) => {
-                                        snapshot.set_value(
-                                            key.clone(),
-                                            self.gauge.value(key.clone())
-                                        );
-                                    },
-                                    Facet::TimingPercentile(ref key) => {
-                                        match self.histogram.snapshot(key.clone()) {
-                                            Some(hs) => {
-                                                snapshot.set_timing_percentiles(key.clone(), hs, &self.percentiles)
-                                            },
-                                            None => {},
-                                        }
-                                    },
-                                    Facet::ValuePercentile(ref key) => {
-                                        match self.histogram.snapshot(key.clone()) {
-                                            Some(hs) => {
-                                                snapshot.set_value_percentiles(key.clone(), hs, &self.percentiles)
-                                            },
-                                            None => {},
-                                        }
-                                    },
-								}
-							}
+                            let snapshot = self.get_snapshot();
+                            let _ = tx.send(snapshot);
+                        },
+                        ControlMessage::DeltaSnapshot(subscriber, tx) => {
+                            let snapshot = self.get_delta_snapshot(subscriber);
+                            let _ = tx.send(snapshot);
+                        },
+                        ControlMessage::Unsubscribe(subscriber) => {
+                            self.subscriptions.remove(&subscriber);
+                        },
+                        ControlMessage::Shutdown(tx) => {
+                            // Close both channels first, so nothing can be sent after the
+                            // drain, then make sure everything sent before the shutdown is
+                            // accounted for.
+                            self.data_rx.close();
+                            self.control_rx.close();
+                            while let Ok(results) = self.data_rx.recv() {
+                                self.process_batch(results);
+                            }
+
+                            // Observers get the final word, too.
+                            let snapshot = self.get_snapshot();
+                            let now = self.conf.clock.now();
+                            for observer in &mut self.observers {
+                                observer.observe(&snapshot, now);
+                            }
+
                             let _ = tx.send(snapshot);
+                            self.shutdown = true;
+
+                            // Any requests that raced with the shutdown are dropped, so their
+                            // callers see the receiver as gone rather than timing out.
+                            while self.control_rx.recv().is_ok() {}
                         },
                     }
                 }
@@ -168,26 +243,296 @@ impl<T: Send + Eq + Hash + Display + Clone> Receiver<T> {
         }
     }
 
-    /// Runs the receiver endlessly.
+    fn process_batch(&mut self, mut results: Vec<(Sample<T>, Labels)>) {
+        for (sample, labels) in &results {
+            if !sample.is_valid() {
+                self.invalid += 1;
+                continue;
+            }
+            if sample.is_clamped() {
+                self.clamped += 1;
+            }
+
+            if self.conf.auto_registration.is_some() {
+                self.auto_register(sample);
+            }
+
+            self.counter.update(sample, labels);
+            self.gauge.update(sample, labels);
+            self.histogram.update(sample, labels);
+        }
+        self.samples_processed += results.len() as u64;
+        self.batches_processed += 1;
+        results.clear();
+        self.buffer_pool_tx.send(results);
+    }
+
+    /// Registers the default facets for the sample's key, if the key isn't known yet.
+    fn auto_register(&mut self, sample: &Sample<T>) {
+        let key = sample.key();
+        if self.known.contains(key) {
+            return;
+        }
+
+        let facets = match self.conf.auto_registration {
+            Some(ref auto) if self.auto_registered < auto.limit => auto.facets(sample).to_vec(),
+            _ => return,
+        };
+
+        // Only keys that actually got a facet count towards the limit.
+        let mut registered = false;
+        for kind in facets {
+            registered |= self.register_facet(kind.facet(key.clone()), None);
+        }
+        if registered {
+            self.auto_registered += 1;
+        }
+        // Even with no facets to register, or none admitted, the key is now known, so that its
+        // samples don't retry the registration.
+        self.known.insert(key.clone());
+    }
+
+    /// Forgets a key once none of its facets are registered, so that it can be auto-registered
+    /// again.
+    fn forget_if_unregistered(&mut self, key: T) {
+        let registered = [
+            Facet::Count(key.clone()),
+            Facet::Gauge(key.clone()),
+            Facet::TimingPercentile(key.clone()),
+            Facet::ValuePercentile(key.clone()),
+        ];
+        if !registered.iter().any(|facet| self.facets.contains(facet)) {
+            self.known.remove(&key);
+        }
+    }
+
+    /// Gets a snapshot of the current metric state.
+    fn get_snapshot(&mut self) -> Snapshot<T> {
+        let started = self.conf.clock.now();
+        let mut snapshot = Snapshot::new();
+        for facet in &self.facets {
+            match *facet {
+                Facet::Count(ref key) => {
+                    for (labels, value) in self.counter.values(key) {
+                        snapshot.set_count(Key::new(key.clone(), labels), value);
+                    }
+                },
+                Facet::Gauge(ref key) => {
+                    for (labels, level) in self.gauge.values(key) {
+                        match level {
+                            Level::Signed(value) => snapshot.set_value(Key::new(key.clone(), labels), value),
+                            Level::Float(value) => snapshot.set_float_value(Key::new(key.clone(), labels), value),
+                        }
+                    }
+                },
+                Facet::TimingPercentile(ref key) => {
+                    let percentiles = self.histogram.percentiles(key).unwrap_or(&self.conf.percentiles);
+                    let encoded = self.histogram.encoded(key);
+                    for (labels, hs) in self.histogram.snapshots(key) {
+                        let mut summary = Summary::from_histogram(&hs, percentiles);
+                        if encoded {
+                            summary = summary.with_encoded(&hs);
+                            if summary.encoded().is_none() {
+                                self.encoding_failures += 1;
+                            }
+                        }
+                        snapshot.set_timing_summary(Key::new(key.clone(), labels), summary);
+                    }
+                },
+                Facet::ValuePercentile(ref key) => {
+                    let percentiles = self.histogram.percentiles(key).unwrap_or(&self.conf.percentiles);
+                    let scale = self.histogram.scale(key);
+                    let encoded = self.histogram.encoded(key);
+                    for (labels, hs) in self.histogram.snapshots(key) {
+                        let mut summary = Summary::from_histogram(&hs, percentiles).with_scale(scale);
+                        if encoded {
+                            summary = summary.with_encoded(&hs);
+                            if summary.encoded().is_none() {
+                                self.encoding_failures += 1;
+                            }
+                        }
+                        snapshot.set_value_summary(Key::new(key.clone(), labels), summary);
+                    }
+                },
+            }
+        }
+
+        if self.conf.instrumentation {
+            self.set_instrumentation(&mut snapshot);
+        }
+
+        self.snapshot_duration = self.conf.clock.now().duration_since(started);
+        snapshot
+    }
+
+    /// Adds the self-instrumentation metrics to a snapshot.
+    ///
+    /// The snapshot duration is that of the previous snapshot, as this one isn't finished yet.
+    fn set_instrumentation(&self, snapshot: &mut Snapshot<T>) {
+        let dropped = self.stats.dropped_samples.load(Ordering::Relaxed);
+        snapshot.set_internal_count("hotmic_dropped_samples", dropped as i64);
+        snapshot.set_internal_count("hotmic_rejected_registrations", self.rejected as i64);
+        snapshot.set_internal_count("hotmic_evicted_keys", self.evicted as i64);
+        let rejected_series = self.counter.rejected_series() + self.gauge.rejected_series() + self.histogram.rejected_series();
+        snapshot.set_internal_count("hotmic_rejected_series", rejected_series as i64);
+        snapshot.set_internal_count("hotmic_invalid_samples", self.invalid as i64);
+        snapshot.set_internal_count("hotmic_clamped_samples", self.clamped as i64);
+        snapshot.set_internal_count("hotmic_encoding_failures", self.encoding_failures as i64);
+
+        let dropped_batches = self.stats.dropped_batches.load(Ordering::Relaxed);
+        let full_sends = self.stats.full_sends.load(Ordering::Relaxed);
+        snapshot.set_internal_count("hotmic_samples_processed", self.samples_processed as i64);
+        snapshot.set_internal_count("hotmic_batches_processed", self.batches_processed as i64);
+        snapshot.set_internal_count("hotmic_dropped_batches", dropped_batches as i64);
+        snapshot.set_internal_count("hotmic_full_sends", full_sends as i64);
+        snapshot.set_internal_value("hotmic_snapshot_duration_ns", duration_as_nanos(self.snapshot_duration));
+        snapshot.set_internal_value("hotmic_buffer_pool_available", self.buffer_pool_rx.len() as u64);
+        snapshot.set_internal_value("hotmic_registered_counters", self.counter.len() as u64);
+        snapshot.set_internal_value("hotmic_registered_gauges", self.gauge.len() as u64);
+        snapshot.set_internal_value("hotmic_registered_histograms", self.histogram.len() as u64);
+    }
+
+    /// Gets a snapshot with counter deltas since the last delta snapshot for the given subscriber.
+    fn get_delta_snapshot(&mut self, subscriber: String) -> Snapshot<T> {
+        let now = self.conf.clock.now();
+        let mut snapshot = self.get_snapshot();
+        let counts = snapshot
+            .measurements()
+            .iter()
+            .filter_map(|m| match *m {
+                TypedMeasurement::Count(ref key, value) => Some((key.clone(), value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let (since, previous) = match self.subscriptions.remove(&subscriber) {
+            Some(subscription) => (subscription.taken, subscription.counts),
+            None => (self.started, FnvHashMap::default()),
+        };
+
+        let deltas = counts
+            .iter()
+            .map(|(key, value)| {
+                let previous = previous.get(key).cloned().unwrap_or(0);
+                (key.clone(), value.wrapping_sub(previous))
+            })
+            .collect();
+        snapshot.set_deltas(now.duration_since(since), deltas);
+
+        let subscription = Subscription {
+            taken: now,
+            counts: counts.into_iter().collect(),
+        };
+        self.subscriptions.insert(subscriber, subscription);
+
+        snapshot
+    }
+
+    /// Runs the receiver until it is shut down.
+    ///
+    /// The receiver can be shut down via `Controller::shutdown`, otherwise it runs endlessly.
     pub fn run(&mut self) {
-        loop {
+        while !self.shutdown {
             self.turn();
         }
     }
 
+    /// Registers an observer, to be invoked with a snapshot every `interval`.
+    ///
+    /// Observers are invoked from within `turn`, so they can be invoked no more often than turns
+    /// happen: with the default poll delay, that's roughly every 100ms when idle.  Every observer is
+    /// also invoked with the final snapshot when the receiver is shut down.
+    ///
+    /// This allows push-based exporters to run without their own thread, or any round trips
+    /// through a `Controller`.
+    pub fn add_observer<O: Observer<T> + 'static>(&mut self, interval: Duration, observer: O) {
+        let scheduled = Scheduled::new(Box::new(observer), interval, self.conf.clock.now());
+        self.observers.push(scheduled);
+    }
+
     /// Registers a facet with the receiver.
     pub fn add_facet(&mut self, facet: Facet<T>) {
-        match facet.clone() {
+        self.register_facet(facet, None);
+    }
+
+    /// Registers a facet with the receiver, using the given histogram configuration.
+    ///
+    /// For timing and value percentile facets, the histogram backing the metric is configured with
+    /// `conf` rather than the defaults from `Configuration`.  This allows, for example, keeping a
+    /// low-precision histogram for a high-cardinality metric while keeping a high-precision one
+    /// elsewhere.  As both percentile facets for a metric share a single histogram, the
+    /// configuration only takes effect if the metric did not already have one.
+    ///
+    /// For other facets, `conf` is ignored.
+    pub fn add_facet_with_histogram(&mut self, facet: Facet<T>, conf: HistogramConfiguration) {
+        self.register_facet(facet, Some(conf));
+    }
+
+    /// Registers a facet, returning whether it got a key of its own.
+    fn register_facet(&mut self, facet: Facet<T>, conf: Option<HistogramConfiguration>) -> bool {
+        // Histograms with their own, finer granularity need upkeep more often.
+        if let Some(ref conf) = conf {
+            match facet {
+                Facet::TimingPercentile(_) | Facet::ValuePercentile(_) => {
+                    self.upkeep_interval = self.upkeep_interval.min(conf.granularity);
+                }
+                _ => {}
+            }
+        }
+
+        let registration = match facet.clone() {
             Facet::Count(t) => self.counter.register(t),
             Facet::Gauge(t) => self.gauge.register(t),
-            Facet::TimingPercentile(t) => self.histogram.register(t),
-            Facet::ValuePercentile(t) => self.histogram.register(t),
-        }
+            Facet::TimingPercentile(t) => self.histogram.register(t, conf),
+            Facet::ValuePercentile(t) => self.histogram.register(t, conf),
+        };
 
-        self.facets.insert(facet);
+        self.known.insert(facet.key().clone());
+        match registration {
+            Registration::Registered => {
+                self.facets.insert(facet);
+                true
+            },
+            Registration::Rejected => {
+                self.rejected += 1;
+                false
+            },
+            Registration::Evicted(evicted) => {
+                // Percentile facets share their histograms, so evicting one evicts both.
+                self.evicted += 1;
+                match facet {
+                    Facet::Count(_) => {
+                        self.facets.remove(&Facet::Count(evicted.clone()));
+                    },
+                    Facet::Gauge(_) => {
+                        self.facets.remove(&Facet::Gauge(evicted.clone()));
+                    },
+                    Facet::TimingPercentile(_) | Facet::ValuePercentile(_) => {
+                        self.facets.remove(&Facet::TimingPercentile(evicted.clone()));
+                        self.facets.remove(&Facet::ValuePercentile(evicted.clone()));
+                    },
+                }
+                self.facets.insert(facet);
+                self.forget_if_unregistered(evicted);
+                true
+            },
+            Registration::Overflowed(overflow) => {
+                self.rejected += 1;
+                let overflow = match facet {
+                    Facet::Count(_) => Facet::Count(overflow),
+                    Facet::Gauge(_) => Facet::Gauge(overflow),
+                    Facet::TimingPercentile(_) => Facet::TimingPercentile(overflow),
+                    Facet::ValuePercentile(_) => Facet::ValuePercentile(overflow),
+                };
+                self.facets.insert(overflow);
+                false
+            },
+        }
     }
 
     /// Deregisters a facet from the receiver.
+    ///
+    /// Once a key has no facets left, it can be auto-registered again.
     pub fn remove_facet(&mut self, facet: Facet<T>) {
         match facet.clone() {
             Facet::Count(t) => self.counter.deregister(t),
@@ -197,5 +542,728 @@ impl<T: Send + Eq + Hash + Display + Clone> Receiver<T> {
         }
 
         self.facets.remove(&facet);
+        self.forget_if_unregistered(facet.key().clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Receiver;
+    use clock::MockClock;
+    use control::Controller;
+    use configuration::{AutoRegistration, BackpressurePolicy, CardinalityPolicy, HistogramConfiguration};
+    use data::{Facet, FacetKind, Labels, MergedHistogram, Percentile, Sample, Snapshot, TypedMeasurement};
+    use error::Error;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_receiver_shutdown_flushes_sinks() {
+        let mut receiver = Receiver::builder().batch_size(128).build();
+        let key = "ok".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let mut flushed = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        // Neither sink fills a batch, so nothing would be sent without flushing.
+        sink.send(Sample::Count(key.clone(), 1)).unwrap();
+        sink.send(Sample::Count(key.clone(), 2)).unwrap();
+        drop(sink);
+
+        flushed.send(Sample::Count(key.clone(), 3)).unwrap();
+        flushed.flush().unwrap();
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&key), Some(&6));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_observers() {
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .poll_delay(Some(Duration::from_millis(5)))
+            .build();
+        let key = "ok".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+
+        let (tx, rx) = mpsc::channel();
+        let observed_key = key.clone();
+        receiver.add_observer(Duration::from_millis(10), move |snapshot: &Snapshot<String>| {
+            let _ = tx.send(snapshot.count(&observed_key).cloned());
+        });
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        // Observers run on their own, without anyone asking for a snapshot.
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Some(0));
+
+        sink.send(Sample::Count(key.clone(), 3)).unwrap();
+        loop {
+            if rx.recv_timeout(Duration::from_secs(5)).unwrap() == Some(3) {
+                break;
+            }
+        }
+
+        // The final snapshot is observed on shutdown, too.
+        sink.send(Sample::Count(key.clone(), 4)).unwrap();
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+        assert_eq!(rx.iter().last(), Some(Some(7)));
+    }
+
+    #[test]
+    fn test_receiver_shared_gauge() {
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        let key = "in_flight".to_owned();
+        receiver.add_facet(Facet::Gauge(key.clone()));
+
+        let controller = receiver.get_controller();
+        let handles = (0..2)
+            .map(|i| {
+                let mut sink = receiver.get_sink();
+                let key = key.clone();
+                thread::spawn(move || {
+                    // Each thread adjusts the same gauge, without coordinating with the other.
+                    let delta = if i == 0 { 3 } else { -1 };
+                    for _ in 0..100 {
+                        sink.send(Sample::ValueDelta(key.clone(), delta)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let handle = thread::spawn(move || receiver.run());
+
+        for sink_handle in handles {
+            sink_handle.join().unwrap();
+        }
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.value(&key), Some(&200));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_float_values() {
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        let key = "load".to_owned();
+        receiver.add_facet(Facet::Gauge(key.clone()));
+        receiver.add_facet_with_histogram(Facet::ValuePercentile(key.clone()), HistogramConfiguration::new().scale(100.0));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        sink.send(Sample::FloatValue(key.clone(), 0.5)).unwrap();
+        sink.send(Sample::FloatValue(key.clone(), 1.25)).unwrap();
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.float_value(&key), Some(1.25));
+
+        let summary = snapshot.value_summary(&key).unwrap();
+        assert_eq!(summary.scale(), 100.0);
+        assert_eq!(summary.scaled_min(), 0.5);
+        assert_eq!(summary.scaled_max(), 1.25);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_clamped_values() {
+        let mut receiver = Receiver::builder().batch_size(1).instrumentation(true).build();
+        let key = "bytes".to_owned();
+        receiver.add_facet(Facet::Gauge(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        // Gauges are signed, so the largest values are clamped, and counted as such.
+        sink.send(Sample::Value(key.clone(), u64::MAX)).unwrap();
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.value(&key), Some(&i64::MAX));
+        assert_eq!(snapshot.internal_count("hotmic_clamped_samples"), Some(&1));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_timing_samples() {
+        let mut receiver = Receiver::builder().batch_size(1).instrumentation(true).build();
+        let key = "query".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        let start = Instant::now();
+        let end = start + Duration::from_nanos(500);
+        sink.send(Sample::TimingDuration(key.clone(), Duration::from_nanos(1_500), 2)).unwrap();
+        sink.send(Sample::Timing(key.clone(), start, end, 1)).unwrap();
+        sink.send(Sample::Timing(key.clone(), end, start, 4)).unwrap();
+
+        // The reversed timing is counted as invalid, and otherwise ignored.
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&key), Some(&3));
+        assert_eq!(snapshot.internal_count("hotmic_invalid_samples"), Some(&1));
+
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!((summary.count(), summary.min(), summary.max()), (2, 500, 1_500));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_encoded_histograms() {
+        let mut receivers = (0..2)
+            .map(|_| {
+                let mut receiver = Receiver::builder()
+                    .batch_size(1)
+                    .histogram_encoded(true)
+                    .build();
+                receiver.add_facet(Facet::ValuePercentile("size".to_owned()));
+                receiver
+            })
+            .collect::<Vec<_>>();
+
+        // One host only ever sees small values, and the other only large ones.
+        let mut merged = MergedHistogram::new();
+        for (i, mut receiver) in receivers.drain(..).enumerate() {
+            let mut sink = receiver.get_sink();
+            let controller = receiver.get_controller();
+            let handle = thread::spawn(move || receiver.run());
+
+            for _ in 0..(1 + i * 2) {
+                sink.send(Sample::Value("size".to_owned(), 10 + i as u64 * 90)).unwrap();
+            }
+
+            let snapshot = controller.shutdown().unwrap();
+            merged.merge_summary(snapshot.value_summary(&"size".to_owned()).unwrap()).unwrap();
+            handle.join().unwrap();
+        }
+
+        let summary = merged.summary(&[Percentile("p50".to_owned(), 50.0)]).unwrap();
+        assert_eq!(summary.count(), 3 + 1);
+        assert_eq!(summary.percentile("p50"), Some(&100));
+    }
+
+    #[test]
+    fn test_receiver_delta_snapshots() {
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        let key = "ok".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        // Samples and snapshot requests travel separately, so wait for samples to land first.
+        let wait_for = |value| while controller.get_snapshot().unwrap().count(&key) != Some(&value) {
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        // The first delta for a subscriber covers everything so far.
+        sink.send(Sample::Count(key.clone(), 5)).unwrap();
+        wait_for(5);
+        let first = controller.get_delta_snapshot("a").unwrap();
+        assert_eq!(first.count(&key), Some(&5));
+        assert_eq!(first.count_delta(&key).unwrap().delta(), 5);
+        assert!(first.interval().is_some());
+
+        // Subscribers are tracked independently of each other.
+        sink.send(Sample::Count(key.clone(), 3)).unwrap();
+        wait_for(8);
+        let second = controller.get_delta_snapshot("a").unwrap();
+        assert_eq!(second.count(&key), Some(&8));
+        assert_eq!(second.count_delta(&key).unwrap().delta(), 3);
+        let other = controller.clone().get_delta_snapshot("b").unwrap();
+        assert_eq!(other.count_delta(&key).unwrap().delta(), 8);
+
+        let unchanged = controller.get_delta_snapshot("a").unwrap();
+        assert_eq!(unchanged.count_delta(&key).unwrap().delta(), 0);
+        assert_eq!(unchanged.count_delta(&key).unwrap().rate(), 0.0);
+
+        // Once unsubscribed, a subscriber starts over.
+        controller.unsubscribe("a").unwrap();
+        let restarted = controller.get_delta_snapshot("a").unwrap();
+        assert_eq!(restarted.count_delta(&key).unwrap().delta(), 8);
+
+        // Plain snapshots carry no deltas, and don't affect any subscriber.
+        let plain = controller.get_snapshot().unwrap();
+        assert!(plain.deltas().is_empty());
+        assert_eq!(plain.interval(), None);
+
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_auto_registration() {
+        let auto = AutoRegistration::new().value(vec![FacetKind::Gauge, FacetKind::ValuePercentile]).limit(3);
+        let mut receiver = Receiver::builder().batch_size(1).auto_registration(auto).build();
+
+        // Explicitly registered keys keep their facets.
+        let explicit = "explicit".to_owned();
+        receiver.add_facet(Facet::Gauge(explicit.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        let (timing, count, value, over) = ("timing".to_owned(), "count".to_owned(), "value".to_owned(), "over".to_owned());
+        sink.send(Sample::Timing(timing.clone(), Instant::now(), Instant::now(), 2)).unwrap();
+        sink.send(Sample::Count(count.clone(), 5)).unwrap();
+        sink.send(Sample::Count(count.clone(), 1)).unwrap();
+        sink.send(Sample::Value(value.clone(), 7)).unwrap();
+        sink.send(Sample::Count(explicit.clone(), 1)).unwrap();
+        // The limit has been reached, so this key is ignored.
+        sink.send(Sample::Count(over.clone(), 1)).unwrap();
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&timing), Some(&2));
+        assert!(snapshot.timing_summary(&timing).is_some());
+        assert_eq!(snapshot.count(&count), Some(&6));
+        assert!(snapshot.value(&count).is_none());
+        assert_eq!(snapshot.value(&value), Some(&7));
+        assert_eq!(snapshot.value_summary(&value).map(|s| s.count()), Some(1));
+        assert!(snapshot.count(&explicit).is_none());
+        assert!(snapshot.count(&over).is_none());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_auto_registration_bookkeeping() {
+        let auto = AutoRegistration::new().limit(2);
+        let mut receiver = Receiver::builder().counter_limit(1).auto_registration(auto).build();
+        let (a, b, c) = ("a".to_owned(), "b".to_owned(), "c".to_owned());
+
+        receiver.auto_register(&Sample::Count(a.clone(), 1));
+        assert_eq!(receiver.auto_registered, 1);
+
+        // A key rejected by the counter limit doesn't use up the auto-registration limit.
+        receiver.auto_register(&Sample::Count(b.clone(), 1));
+        assert_eq!(receiver.auto_registered, 1);
+        assert!(!receiver.facets.contains(&Facet::Count(b.clone())));
+
+        // Once removed, a key can be auto-registered again.
+        receiver.remove_facet(Facet::Count(a.clone()));
+        receiver.auto_register(&Sample::Count(a.clone(), 1));
+        assert_eq!(receiver.auto_registered, 2);
+        assert!(receiver.facets.contains(&Facet::Count(a.clone())));
+
+        // Explicitly registered keys are left alone, until their facets are removed.
+        let mut receiver = Receiver::builder().auto_registration(AutoRegistration::new()).build();
+        let explicit = "explicit".to_owned();
+        receiver.add_facet(Facet::Gauge(explicit.clone()));
+        receiver.auto_register(&Sample::Count(explicit.clone(), 1));
+        assert!(!receiver.facets.contains(&Facet::Count(explicit.clone())));
+        receiver.remove_facet(Facet::Gauge(explicit.clone()));
+        receiver.auto_register(&Sample::Count(explicit.clone(), 1));
+        assert!(receiver.facets.contains(&Facet::Count(explicit)));
+
+        // Keys evicted to make room are forgotten, too.
+        let mut receiver = Receiver::builder()
+            .counter_limit(1)
+            .cardinality_policy(CardinalityPolicy::EvictLru)
+            .auto_registration(AutoRegistration::new())
+            .build();
+        receiver.auto_register(&Sample::Count(a.clone(), 1));
+        receiver.auto_register(&Sample::Count(c.clone(), 1));
+        assert!(!receiver.known.contains(&a));
+        receiver.auto_register(&Sample::Count(a.clone(), 1));
+        assert!(receiver.facets.contains(&Facet::Count(a)));
+        assert!(!receiver.facets.contains(&Facet::Count(c)));
+    }
+
+    #[test]
+    fn test_receiver_cardinality_limits() {
+        let overflow = "overflow".to_owned();
+        let mut receiver = Receiver::builder()
+            .counter_limit(2)
+            .histogram_limit(1)
+            .cardinality_policy(CardinalityPolicy::Overflow(overflow.clone()))
+            .instrumentation(true)
+            .build();
+
+        let keys = vec!["a".to_owned(), "b".to_owned(), "c".to_owned(), "d".to_owned()];
+        for key in &keys {
+            receiver.add_facet(Facet::Count(key.clone()));
+            receiver.add_facet(Facet::TimingPercentile(key.clone()));
+        }
+        // Gauges aren't limited.
+        for key in &keys {
+            receiver.add_facet(Facet::Gauge(key.clone()));
+        }
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        for key in &keys {
+            sink.send(Sample::Count(key.clone(), 1)).unwrap();
+            sink.send(Sample::Value(key.clone(), 1)).unwrap();
+        }
+        sink.flush().unwrap();
+
+        // Value samples are counted, too.
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&keys[0]), Some(&2));
+        assert_eq!(snapshot.count(&keys[1]), Some(&2));
+        assert_eq!(snapshot.count(&keys[2]), None);
+        assert_eq!(snapshot.count(&overflow), Some(&4));
+        assert!(snapshot.timing_summary(&keys[0]).is_some());
+        assert!(snapshot.timing_summary(&keys[1]).is_none());
+        assert!(snapshot.timing_summary(&overflow).is_some());
+        assert!(keys.iter().all(|key| snapshot.value(key).is_some()));
+
+        // Two counters and three histograms were folded into the overflow key.
+        assert_eq!(snapshot.internal_count("hotmic_rejected_registrations"), Some(&5));
+        assert_eq!(snapshot.internal_count("hotmic_evicted_keys"), Some(&0));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_series_limit() {
+        let mut receiver = Receiver::builder().series_limit(2).instrumentation(true).build();
+        let key = "requests".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+        receiver.add_facet(Facet::Gauge(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        // A label with unbounded values only ever gets as many series as the limit allows.
+        for user in 0..10 {
+            let labels = Labels::new().with("user", user.to_string());
+            sink.send_with_labels(Sample::Count(key.clone(), 1), labels.clone()).unwrap();
+            sink.send_with_labels(Sample::Value(key.clone(), 1), labels).unwrap();
+        }
+        sink.send(Sample::Count(key.clone(), 5)).unwrap();
+        sink.flush().unwrap();
+
+        let snapshot = controller.shutdown().unwrap();
+        let mut counts = snapshot
+            .measurements()
+            .iter()
+            .filter_map(|m| match *m {
+                TypedMeasurement::Count(ref k, value) => Some((k.to_string(), value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        counts.sort();
+        // Value samples are counted, too.
+        assert_eq!(counts, vec![
+            ("requests".to_owned(), 5),
+            ("requests{user=\"0\"}".to_owned(), 2),
+            ("requests{user=\"1\"}".to_owned(), 2),
+        ]);
+        let gauges = snapshot.measurements().iter().filter(|m| matches!(**m, TypedMeasurement::Gauge(..)));
+        assert_eq!(gauges.count(), 3);
+        // Eight users, with two samples for the counter and one for the gauge each.
+        assert_eq!(snapshot.internal_count("hotmic_rejected_series"), Some(&24));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_cardinality_eviction() {
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .counter_limit(1)
+            .cardinality_policy(CardinalityPolicy::EvictLru)
+            .instrumentation(true)
+            .build();
+
+        let (a, b) = ("a".to_owned(), "b".to_owned());
+        receiver.add_facet(Facet::Count(a.clone()));
+        receiver.add_facet(Facet::Count(b.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        sink.send(Sample::Count(a.clone(), 1)).unwrap();
+        sink.send(Sample::Count(b.clone(), 1)).unwrap();
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&a), None);
+        assert_eq!(snapshot.count(&b), Some(&1));
+        assert_eq!(snapshot.measurements().len(), 1);
+        assert_eq!(snapshot.internal_count("hotmic_evicted_keys"), Some(&1));
+        assert_eq!(snapshot.internal_count("hotmic_rejected_registrations"), Some(&0));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_instrumentation() {
+        let mut receiver = Receiver::builder().capacity(4).batch_size(2).instrumentation(true).build();
+        receiver.add_facet(Facet::Count("a".to_owned()));
+        receiver.add_facet(Facet::Gauge("a".to_owned()));
+        receiver.add_facet(Facet::ValuePercentile("b".to_owned()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        for i in 0..5 {
+            sink.send(Sample::Value("a".to_owned(), i)).unwrap();
+        }
+        drop(sink);
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.internal_count("hotmic_samples_processed"), Some(&5));
+        assert_eq!(snapshot.internal_count("hotmic_batches_processed"), Some(&3));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_batches"), Some(&0));
+        assert_eq!(snapshot.internal_count("hotmic_full_sends"), Some(&0));
+        assert_eq!(snapshot.internal_count("hotmic_encoding_failures"), Some(&0));
+        assert_eq!(snapshot.internal_value("hotmic_buffer_pool_available"), Some(&4));
+        assert_eq!(snapshot.internal_value("hotmic_registered_counters"), Some(&1));
+        assert_eq!(snapshot.internal_value("hotmic_registered_gauges"), Some(&1));
+        assert_eq!(snapshot.internal_value("hotmic_registered_histograms"), Some(&1));
+        assert!(snapshot.internal_value("hotmic_snapshot_duration_ns").is_some());
+        handle.join().unwrap();
+
+        // Without instrumentation, there are no internal metrics at all.
+        let mut receiver = Receiver::<String>::builder().build();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+        let snapshot = controller.shutdown().unwrap();
+        assert!(snapshot.internal_measurements().is_empty());
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), None);
+        handle.join().unwrap();
+    }
+
+    /// Makes sure the receiver has started a turn, and so run upkeep, since this was called.
+    fn settle(controller: &Controller<String>) {
+        // The reply to the first snapshot is sent from within a turn that may have started
+        // earlier, but the second can only be handled by a turn that starts after it.
+        controller.get_snapshot().unwrap();
+        controller.get_snapshot().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_mock_clock_window() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .histogram_window(Duration::from_secs(5))
+            .histogram_granularity(Duration::from_secs(1))
+            .clock(clock.clone())
+            .build();
+        let key = "size".to_owned();
+        receiver.add_facet(Facet::ValuePercentile(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        sink.send(Sample::Value(key.clone(), 42)).unwrap();
+        while controller.get_snapshot().unwrap().value_summary(&key).map(|s| s.count()) != Some(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // With a spare bucket, values stay in the window for five whole seconds...
+        for _ in 0..5 {
+            clock.advance(Duration::from_secs(1));
+            settle(&controller);
+        }
+        let snapshot = controller.get_snapshot().unwrap();
+        assert_eq!(snapshot.value_summary(&key).unwrap().count(), 1);
+
+        // ...and are gone after the sixth.
+        clock.advance(Duration::from_secs(1));
+        settle(&controller);
+        let snapshot = controller.get_snapshot().unwrap();
+        assert_eq!(snapshot.value_summary(&key).unwrap().count(), 0);
+
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_mock_clock_facet_granularity() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .histogram_window(Duration::from_secs(10))
+            .histogram_granularity(Duration::from_secs(1))
+            .clock(clock.clone())
+            .build();
+        let key = "size".to_owned();
+        let conf = HistogramConfiguration::new()
+            .window(Duration::from_millis(100))
+            .granularity(Duration::from_millis(10));
+        receiver.add_facet_with_histogram(Facet::ValuePercentile(key.clone()), conf);
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+        let count = |controller: &Controller<String>| {
+            controller.get_snapshot().unwrap().value_summary(&key).map(|s| s.count())
+        };
+
+        sink.send(Sample::Value(key.clone(), 42)).unwrap();
+        while count(&controller) != Some(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The finer granularity of this one metric has upkeep run often enough for its window.
+        clock.advance(Duration::from_millis(120));
+        settle(&controller);
+        assert_eq!(count(&controller), Some(0));
+
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_mock_clock_long_idle() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .histogram_window(Duration::from_secs(10))
+            .histogram_granularity(Duration::from_secs(1))
+            .clock(clock.clone())
+            .build();
+        let key = "size".to_owned();
+        receiver.add_facet(Facet::ValuePercentile(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+        let count = |controller: &Controller<String>| {
+            controller.get_snapshot().unwrap().value_summary(&key).map(|s| s.count())
+        };
+
+        sink.send(Sample::Value(key.clone(), 42)).unwrap();
+        while count(&controller) != Some(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // A single stall far longer than the window clears it entirely.
+        clock.advance(Duration::from_secs(30));
+        settle(&controller);
+        assert_eq!(count(&controller), Some(0));
+
+        // New values are tracked as usual afterwards.
+        sink.send(Sample::Value(key.clone(), 42)).unwrap();
+        while count(&controller) != Some(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        clock.advance(Duration::from_secs(9));
+        settle(&controller);
+        assert_eq!(count(&controller), Some(1));
+
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_timeout_and_gone() {
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .snapshot_timeout(Some(Duration::from_millis(50)))
+            .build();
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+
+        // Nothing is running the receiver yet, so nothing can respond.
+        match controller.get_snapshot() {
+            Err(Error::SnapshotTimeout) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        let handle = thread::spawn(move || receiver.run());
+        controller.shutdown().unwrap();
+        handle.join().unwrap();
+
+        match controller.get_snapshot() {
+            Err(Error::ReceiverGone) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        match sink.send(Sample::Count("ok".to_owned(), 1)) {
+            Err(Error::ReceiverGone) => {},
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_receiver_gone_after_shutdown() {
+        let mut receiver = Receiver::builder()
+            .batch_size(1)
+            .capacity(1)
+            .backpressure(BackpressurePolicy::Block)
+            .snapshot_timeout(Some(Duration::from_secs(5)))
+            .build();
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || {
+            receiver.run();
+            receiver
+        });
+        controller.shutdown().unwrap();
+
+        // The receiver is still around, but it's shut down all the same.
+        let _receiver = handle.join().unwrap();
+        match controller.get_snapshot() {
+            Err(Error::ReceiverGone) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        for _ in 0..3 {
+            match sink.send(Sample::Count("ok".to_owned(), 1)) {
+                Err(Error::ReceiverGone) => {},
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_receiver_configured_percentiles() {
+        let p50 = Percentile("p50".to_owned(), 50.0);
+        let p95 = Percentile("p95".to_owned(), 95.0);
+        let p9999 = Percentile("p9999".to_owned(), 99.99);
+
+        let mut receiver = Receiver::builder()
+            .percentiles(vec![p50.clone(), p95.clone()])
+            .build();
+
+        let default_key = "default".to_owned();
+        let slo_key = "slo".to_owned();
+        receiver.add_facet(Facet::TimingPercentile(default_key.clone()));
+        receiver.add_facet_with_histogram(
+            Facet::TimingPercentile(slo_key.clone()),
+            HistogramConfiguration::new().percentiles(vec![p9999.clone()]),
+        );
+
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+        let snapshot = controller.shutdown().unwrap();
+        handle.join().unwrap();
+
+        let summary = snapshot.timing_summary(&default_key).unwrap();
+        let labels = summary.percentiles().iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(labels, vec![p50.clone(), p95.clone()]);
+
+        let summary = snapshot.timing_summary(&slo_key).unwrap();
+        let labels = summary.percentiles().iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(labels, vec![p9999]);
     }
 }
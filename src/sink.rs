@@ -1,65 +1,231 @@
-use std::io;
 use std::hash::Hash;
-use channel;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use channel::{self, SendError};
+use clock::Clock;
+use configuration::{BackpressurePolicy, HistogramConfiguration};
 use control::ControlMessage;
-use data::{Facet, Sample};
-use helper::io_error;
+use data::{Facet, Labels, Sample};
+use error::Error;
 use crossbeam_channel::Receiver;
 
+type Batch<T> = Vec<(Sample<T>, Labels)>;
+
+/// Tallies shared by every `Sink` of a receiver, reported as internal metrics.
+#[derive(Default)]
+pub(crate) struct SinkStats {
+    pub dropped_samples: AtomicUsize,
+    pub dropped_batches: AtomicUsize,
+    pub full_sends: AtomicUsize,
+}
+
 /// An independent handle for sending metric samples into the receiver.
 ///
 /// `Sink` is cloneable, and can not only send metric samples but can register and deregister
 /// metric facets at any time.
+///
+/// Samples are buffered until a full batch is ready to be sent.  Any partial batch is sent when the
+/// `Sink` is dropped, or can be sent at any time with `flush`.  Dropping a `Sink` never blocks: if
+/// the receiver has no room for the partial batch, it is dropped, and counted in the
+/// `hotmic_dropped_samples` internal metric, so call `flush` beforehand to wait for room instead.
 pub struct Sink<T> {
-    buffer_pool_rx: Receiver<Vec<Sample<T>>>,
-    data_tx: channel::Sender<Vec<Sample<T>>>,
+    buffer_pool_rx: Receiver<Batch<T>>,
+    data_tx: channel::Sender<Batch<T>>,
     control_tx: channel::Sender<ControlMessage<T>>,
-    buffer: Option<Vec<Sample<T>>>,
+    buffer: Option<Batch<T>>,
     batch_size: usize,
+    backpressure: BackpressurePolicy,
+    stats: Arc<SinkStats>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<T> Sink<T>
     where T: Eq + Hash
 {
     pub(crate) fn new(
-        buffer_pool_rx: Receiver<Vec<Sample<T>>>,
-        data_tx: channel::Sender<Vec<Sample<T>>>,
+        buffer_pool_rx: Receiver<Batch<T>>,
+        data_tx: channel::Sender<Batch<T>>,
         control_tx: channel::Sender<ControlMessage<T>>,
         batch_size: usize,
+        backpressure: BackpressurePolicy,
+        stats: Arc<SinkStats>,
+        clock: Arc<dyn Clock>,
     ) -> Sink<T> {
         Sink {
-            buffer_pool_rx: buffer_pool_rx,
-            data_tx: data_tx,
-            control_tx: control_tx,
+            buffer_pool_rx,
+            data_tx,
+            control_tx,
             buffer: None,
-            batch_size: batch_size,
+            batch_size,
+            backpressure,
+            stats,
+            clock,
         }
     }
 
     /// Sends a metric sample to the receiver.
-    pub fn send(&mut self, sample: Sample<T>) -> Result<(), io::Error> {
+    ///
+    /// If no buffer is available to hold the sample, the configured `BackpressurePolicy` decides
+    /// whether this blocks or drops samples.
+    pub fn send(&mut self, sample: Sample<T>) -> Result<(), Error> {
+        self.send_with_labels(sample, Labels::new())
+    }
+
+    /// Sends a metric sample, with the given labels, to the receiver.
+    ///
+    /// Each distinct set of labels for a metric key is tracked as its own series, but all of them
+    /// share the facets registered for the key.
+    pub fn send_with_labels(&mut self, sample: Sample<T>, labels: Labels) -> Result<(), Error> {
+        self.push(sample, labels, true)
+    }
+
+    /// Sends a metric sample to the receiver, without blocking.
+    ///
+    /// Unlike `send`, this never blocks, and never drops samples, regardless of the configured
+    /// `BackpressurePolicy`: if no buffer is available to hold the sample,
+    /// `Error::BufferPoolExhausted` is returned, and if the sample completes a batch that the
+    /// receiver has no room for, `Error::ChannelFull` is returned.  Either way, the sample is not
+    /// sent, and the buffer never grows past the batch size.
+    pub fn try_send(&mut self, sample: Sample<T>) -> Result<(), Error> {
+        self.try_send_with_labels(sample, Labels::new())
+    }
+
+    /// Sends a metric sample, with the given labels, to the receiver, without blocking.
+    ///
+    /// See `try_send` for details.
+    pub fn try_send_with_labels(&mut self, sample: Sample<T>, labels: Labels) -> Result<(), Error> {
+        self.push(sample, labels, false)
+    }
+
+    fn push(&mut self, sample: Sample<T>, labels: Labels, blocking: bool) -> Result<(), Error> {
         let mut buffer = match self.buffer.take() {
-            None => {
-                self.buffer_pool_rx.recv()
-                    .ok_or(io_error("failed to get sample buffer"))?
-            },
             Some(buffer) => buffer,
+            None => match self.acquire_buffer(blocking)? {
+                Some(buffer) => buffer,
+                None => {
+                    self.stats.dropped_samples.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                },
+            },
         };
 
-        buffer.push(sample);
+        buffer.push((sample, labels));
         if buffer.len() >= self.batch_size {
-            self.data_tx.send(buffer)
-                .map_err(|_| io_error("failed to send sample buffer"))?;
+            self.send_buffer(buffer, blocking)
         } else {
             self.buffer = Some(buffer);
+            Ok(())
+        }
+    }
+
+    /// Gets an empty buffer, or `None` if the sample being sent should be dropped.
+    fn acquire_buffer(&mut self, blocking: bool) -> Result<Option<Batch<T>>, Error> {
+        if let Some(buffer) = self.buffer_pool_rx.try_recv() {
+            return Ok(Some(buffer));
         }
 
-        Ok(())
+        match self.backpressure {
+            BackpressurePolicy::Grow => Ok(Some(Vec::with_capacity(self.batch_size))),
+            _ if !blocking => Err(Error::BufferPoolExhausted),
+            BackpressurePolicy::Block if self.data_tx.is_closed() => Err(Error::ReceiverGone),
+            BackpressurePolicy::Block => self.buffer_pool_rx.recv()
+                .map(Some)
+                .ok_or(Error::ReceiverGone),
+            BackpressurePolicy::DropNewest => Ok(None),
+            BackpressurePolicy::DropOldest => Ok(self.steal_oldest()),
+        }
+    }
+
+    /// Starts timing an operation for the given metric key.
+    ///
+    /// The returned guard records the current time, and sends a `Sample::Timing` -- with a count
+    /// of 1 -- covering the time between its creation and when it is dropped.  Time is read from
+    /// the receiver's configured clock.
+    ///
+    /// The guard sends through its own clone of this sink, so this sink can keep sending samples
+    /// while the guard is alive.  That clone doesn't share this sink's buffer, though: the sample
+    /// goes out as a batch of its own when the guard is dropped, so every timed operation costs a
+    /// channel send, rather than a push onto a batch.  On hot paths, where that matters, send a
+    /// `Sample::Timing` directly instead: the benchmark example's `--guards` mode shows the
+    /// difference.
+    pub fn time(&self, key: T) -> TimingGuard<T> {
+        self.time_with_count(key, 1)
+    }
+
+    /// Starts timing an operation for the given metric key, with the given count.
+    ///
+    /// Like `time`, but the resulting sample will carry `count`, such as the number of bytes
+    /// processed during the operation.  The count can be changed up until the guard is dropped.
+    pub fn time_with_count(&self, key: T, count: u64) -> TimingGuard<T> {
+        self.start_timing(key, Labels::new(), count)
+    }
+
+    /// Starts timing an operation for the given metric key, with the given labels.
+    ///
+    /// Like `time`, but the resulting sample is sent with `labels`, just as with
+    /// `send_with_labels`.
+    pub fn time_with_labels(&self, key: T, labels: Labels) -> TimingGuard<T> {
+        self.start_timing(key, labels, 1)
+    }
+
+    fn start_timing(&self, key: T, labels: Labels, count: u64) -> TimingGuard<T> {
+        let start = self.clock.now();
+        TimingGuard {
+            sink: self.clone(),
+            key: Some(key),
+            labels,
+            start,
+            count,
+        }
+    }
+
+    /// Starts tracking an in-flight operation for the given metric key.
+    ///
+    /// The returned guard immediately sends a `Sample::ValueDelta` of 1 for the key, and when
+    /// dropped, sends a `Sample::ValueDelta` of -1 along with a `Sample::Timing` covering the
+    /// operation, just as with `time`.  With a `Facet::Gauge` registered for the key, the gauge
+    /// holds the number of operations in progress, across every sink.
+    ///
+    /// Both adjustments are sent right away, rather than waiting for a full batch, through the
+    /// guard's own clone of this sink: every operation tracked costs two channel sends, one when
+    /// the guard is created and one when it is dropped.
+    pub fn in_flight(&self, key: T) -> InFlightGuard<T>
+        where T: Clone
+    {
+        self.in_flight_with_labels(key, Labels::new())
+    }
+
+    /// Starts tracking an in-flight operation for the given metric key, with the given labels.
+    ///
+    /// Like `in_flight`, but every sample is sent with `labels`, just as with `send_with_labels`.
+    pub fn in_flight_with_labels(&self, key: T, labels: Labels) -> InFlightGuard<T>
+        where T: Clone
+    {
+        let mut sink = self.clone();
+        let _ = sink.send_with_labels(Sample::ValueDelta(key.clone(), 1), labels.clone());
+        let _ = sink.flush();
+        let start = self.clock.now();
+        InFlightGuard {
+            sink,
+            key: Some(key),
+            labels,
+            start,
+            timed: true,
+        }
     }
 
     /// Registers a facet with the receiver.
     pub fn add_facet(&mut self, facet: Facet<T>) {
-        let _ = self.control_tx.send(ControlMessage::AddFacet(facet));
+        let _ = self.control_tx.send(ControlMessage::AddFacet(facet, None));
+    }
+
+    /// Registers a facet with the receiver, using the given histogram configuration.
+    ///
+    /// See `Receiver::add_facet_with_histogram` for details.
+    pub fn add_facet_with_histogram(&mut self, facet: Facet<T>, conf: HistogramConfiguration) {
+        let _ = self.control_tx.send(ControlMessage::AddFacet(facet, Some(conf)));
     }
 
     /// Deregisters a facet from the receiver.
@@ -68,6 +234,126 @@ impl<T> Sink<T>
     }
 }
 
+impl<T> Sink<T> {
+    /// Sends any buffered samples to the receiver, without waiting for a full batch.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match self.buffer.take() {
+            Some(buffer) => if buffer.is_empty() {
+                self.buffer = Some(buffer);
+                Ok(())
+            } else {
+                self.send_buffer(buffer, true)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a batch to the receiver, applying the backpressure policy if the data channel is full.
+    ///
+    /// If the batch can't be sent, it is held on to rather than lost, and will go out with the next
+    /// batch, or flush, instead.  When not blocking, the last sample -- the one being sent -- is
+    /// taken back out of a batch that doesn't fit, so the caller can tell it wasn't sent.
+    fn send_buffer(&mut self, buffer: Batch<T>, blocking: bool) -> Result<(), Error> {
+        let mut buffer = buffer;
+        let mut spare = None;
+        let mut full = false;
+        let mut backoff = Backoff::new();
+        loop {
+            buffer = match self.data_tx.send(buffer) {
+                Ok(()) => {
+                    self.buffer = spare;
+                    return Ok(());
+                },
+                Err(SendError::Full(buffer)) => {
+                    if !full {
+                        full = true;
+                        self.stats.full_sends.fetch_add(1, Ordering::Relaxed);
+                    }
+                    buffer
+                },
+                Err(SendError::Disconnected(buffer)) => {
+                    self.buffer = Some(buffer);
+                    return Err(Error::ReceiverGone);
+                },
+                Err(e) => return Err(e.into()),
+            };
+
+            if !blocking {
+                buffer.pop();
+                self.buffer = Some(buffer);
+                return Err(Error::ChannelFull);
+            }
+
+            match self.backpressure {
+                BackpressurePolicy::Block | BackpressurePolicy::Grow => backoff.wait(),
+                BackpressurePolicy::DropNewest => {
+                    self.stats.dropped_samples.fetch_add(buffer.len(), Ordering::Relaxed);
+                    self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+                    buffer.clear();
+                    self.buffer = Some(buffer);
+                    return Ok(());
+                },
+                BackpressurePolicy::DropOldest => match self.steal_oldest() {
+                    Some(stolen) => spare = Some(stolen),
+                    None => backoff.wait(),
+                },
+            }
+        }
+    }
+
+    /// Drops the oldest batch waiting in the data channel, and returns its now-empty buffer.
+    fn steal_oldest(&self) -> Option<Batch<T>> {
+        self.data_tx.steal().map(|mut buffer| {
+            self.stats.dropped_samples.fetch_add(buffer.len(), Ordering::Relaxed);
+            self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+            buffer.clear();
+            buffer
+        })
+    }
+}
+
+/// Waits between attempts at sending into a full data channel.
+///
+/// The first few waits only yield, as the receiver usually catches up quickly, and later ones
+/// sleep, doubling each time up to `MAX_BACKOFF`, so that a stalled receiver isn't met with a
+/// spinning sender.
+struct Backoff {
+    attempts: u32,
+}
+
+const YIELD_ATTEMPTS: u32 = 8;
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff { attempts: 0 }
+    }
+
+    fn wait(&mut self) {
+        if self.attempts < YIELD_ATTEMPTS {
+            thread::yield_now();
+        } else {
+            let shift = (self.attempts - YIELD_ATTEMPTS).min(10);
+            thread::sleep(Duration::from_micros(1 << shift).min(MAX_BACKOFF));
+        }
+        self.attempts = self.attempts.saturating_add(1);
+    }
+}
+
+impl<T> Drop for Sink<T> {
+    fn drop(&mut self) {
+        // Dropping never blocks, whatever the backpressure policy: if the receiver has no room
+        // for the last batch, it is dropped, and counted as such.
+        if let Some(buffer) = self.buffer.take() {
+            let len = buffer.len();
+            if len > 0 && self.data_tx.send(buffer).is_err() {
+                self.stats.dropped_samples.fetch_add(len, Ordering::Relaxed);
+                self.stats.dropped_batches.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
 impl<T> Clone for Sink<T> {
     fn clone(&self) -> Sink<T> {
         Sink {
@@ -76,6 +362,404 @@ impl<T> Clone for Sink<T> {
             control_tx: self.control_tx.clone(),
             buffer: None,
             batch_size: self.batch_size,
+            backpressure: self.backpressure,
+            stats: self.stats.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+/// A guard that times an operation, sending a `Sample::Timing` when dropped.
+///
+/// Created by `Sink::time`, `Sink::time_with_count` and `Sink::time_with_labels`.
+pub struct TimingGuard<T: Eq + Hash> {
+    sink: Sink<T>,
+    key: Option<T>,
+    labels: Labels,
+    start: Instant,
+    count: u64,
+}
+
+impl<T: Eq + Hash> TimingGuard<T> {
+    /// Sets the count that will be sent along with the timing.
+    pub fn set_count(&mut self, count: u64) {
+        self.count = count;
+    }
+
+    /// Cancels the timing, so that no sample is sent.
+    pub fn cancel(mut self) {
+        self.key = None;
+    }
+}
+
+impl<T: Eq + Hash> Drop for TimingGuard<T> {
+    fn drop(&mut self) {
+        // The sample is sent, as a batch of its own, when the guard's sink is dropped right after
+        // this.
+        if let Some(key) = self.key.take() {
+            let end = self.sink.clock.now();
+            let sample = Sample::Timing(key, self.start, end, self.count);
+            let _ = self.sink.send_with_labels(sample, self.labels.clone());
+        }
+    }
+}
+
+/// A guard that tracks an in-flight operation, adjusting a gauge and sending a
+/// `Sample::Timing` when dropped.
+///
+/// Created by `Sink::in_flight` and `Sink::in_flight_with_labels`.
+pub struct InFlightGuard<T: Eq + Hash + Clone> {
+    sink: Sink<T>,
+    key: Option<T>,
+    labels: Labels,
+    start: Instant,
+    timed: bool,
+}
+
+impl<T: Eq + Hash + Clone> InFlightGuard<T> {
+    /// Cancels the timing, so that only the gauge is adjusted when dropped.
+    ///
+    /// The operation is still considered to be in flight until the guard is dropped.
+    pub fn cancel_timing(&mut self) {
+        self.timed = false;
+    }
+}
+
+impl<T: Eq + Hash + Clone> Drop for InFlightGuard<T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let end = self.sink.clock.now();
+            let _ = self.sink.send_with_labels(Sample::ValueDelta(key.clone(), -1), self.labels.clone());
+            if self.timed {
+                let _ = self.sink.send_with_labels(Sample::Timing(key, self.start, end, 1), self.labels.clone());
+            }
+            let _ = self.sink.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sink, SinkStats};
+    use channel;
+    use clock::{MockClock, SystemClock};
+    use configuration::BackpressurePolicy;
+    use crossbeam_channel;
+    use data::{Facet, Labels, Sample, Snapshot, TypedMeasurement};
+    use receiver::Receiver;
+    use control::Controller;
+    use error::Error;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    fn snapshot_until<F>(controller: &Controller<String>, f: F) -> Snapshot<String>
+        where F: Fn(&Snapshot<String>) -> bool
+    {
+        let mut snapshot = controller.get_snapshot().unwrap();
+        for _ in 0..50 {
+            if f(&snapshot) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            snapshot = controller.get_snapshot().unwrap();
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_sink_timing_guard() {
+        let mut receiver = Receiver::builder().batch_size(1).build();
+        let key = "op".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        thread::spawn(move || receiver.run());
+
+        {
+            let _guard = sink.time(key.clone());
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        {
+            let mut guard = sink.time_with_count(key.clone(), 10);
+            guard.set_count(41);
+        }
+
+        sink.time(key.clone()).cancel();
+
+        let snapshot = snapshot_until(&controller, |s| s.count(&key) == Some(&42));
+        assert_eq!(snapshot.count(&key), Some(&42));
+
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!(summary.count(), 2);
+        assert!(summary.max() >= 5_000_000);
+    }
+
+    #[test]
+    fn test_sink_send_during_timing_guard() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder().batch_size(2).clock(clock.clone()).build();
+        let key = "op".to_owned();
+        receiver.add_facet(Facet::Count(key.clone()));
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        {
+            let guard = sink.time_with_count(key.clone(), 5);
+            sink.send(Sample::Count(key.clone(), 2)).unwrap();
+            clock.advance(Duration::from_nanos(1_500));
+            drop(guard);
+            sink.send(Sample::Count(key.clone(), 3)).unwrap();
+        }
+
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&key), Some(&10));
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!(summary.count(), 1);
+        assert_eq!(summary.max(), 1_500);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sink_timing_guard_clock() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder().batch_size(1).clock(clock.clone()).build();
+        let key = "op".to_owned();
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        {
+            let _guard = sink.time(key.clone());
+            clock.advance(Duration::from_nanos(1_500));
+        }
+
+        let snapshot = controller.shutdown().unwrap();
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!(summary.count(), 1);
+        assert_eq!(summary.max(), 1_500);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sink_in_flight_guard() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder().batch_size(10).clock(clock.clone()).build();
+        let (key, sent) = ("requests".to_owned(), "sent".to_owned());
+        receiver.add_facet(Facet::Count(sent.clone()));
+        receiver.add_facet(Facet::Gauge(key.clone()));
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let mut sink = receiver.get_sink();
+        let other = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        {
+            // The adjustments go out right away, even though they'd never fill a batch.
+            let _first = sink.in_flight(key.clone());
+            let mut second = other.in_flight(key.clone());
+            second.cancel_timing();
+            sink.send(Sample::Count(sent.clone(), 3)).unwrap();
+
+            let snapshot = snapshot_until(&controller, |s| s.value(&key) == Some(&2));
+            assert_eq!(snapshot.value(&key), Some(&2));
+            clock.advance(Duration::from_nanos(1_500));
         }
+
+        let snapshot = snapshot_until(&controller, |s| s.value(&key) == Some(&0));
+        assert_eq!(snapshot.value(&key), Some(&0));
+
+        // Only the guard that kept its timing sent one.
+        let summary = snapshot.timing_summary(&key).unwrap();
+        assert_eq!(summary.count(), 1);
+        assert_eq!(summary.max(), 1_500);
+
+        // The count sent while the guards were alive was still waiting on a full batch.
+        assert_eq!(snapshot.count(&sent), Some(&0));
+        sink.flush().unwrap();
+        let snapshot = controller.shutdown().unwrap();
+        assert_eq!(snapshot.count(&sent), Some(&3));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sink_labeled_guards() {
+        let clock = MockClock::new();
+        let mut receiver = Receiver::builder().clock(clock.clone()).build();
+        let key = "requests".to_owned();
+        receiver.add_facet(Facet::Gauge(key.clone()));
+        receiver.add_facet(Facet::TimingPercentile(key.clone()));
+
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        let handle = thread::spawn(move || receiver.run());
+
+        let labels = Labels::new().with("endpoint", "/a");
+        {
+            let _timing = sink.time_with_labels(key.clone(), labels.clone());
+            let _in_flight = sink.in_flight_with_labels(key.clone(), labels.clone());
+            clock.advance(Duration::from_nanos(1_500));
+        }
+
+        let snapshot = controller.shutdown().unwrap();
+        let series = snapshot
+            .measurements()
+            .iter()
+            .filter(|m| m.key().labels() == &labels)
+            .collect::<Vec<_>>();
+        assert_eq!(series.len(), 2);
+        for measurement in series {
+            match *measurement {
+                TypedMeasurement::Gauge(_, value) => assert_eq!(value, 0),
+                TypedMeasurement::TimingPercentile(_, ref summary) => {
+                    assert_eq!(summary.count(), 2);
+                    assert_eq!(summary.max(), 1_500);
+                },
+                ref m => panic!("unexpected measurement {:?}", m),
+            }
+        }
+        handle.join().unwrap();
+    }
+
+    fn run_with_backpressure<F>(policy: BackpressurePolicy, f: F) -> Snapshot<String>
+        where F: FnOnce(&mut ::sink::Sink<String>)
+    {
+        // A single buffer holding a single sample, so the second sample sent has nowhere to go
+        // until the receiver starts running.
+        let mut receiver = Receiver::builder()
+            .capacity(1)
+            .batch_size(1)
+            .backpressure(policy)
+            .instrumentation(true)
+            .build();
+        receiver.add_facet(Facet::Count("ops".to_owned()));
+
+        let mut sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        f(&mut sink);
+
+        let handle = thread::spawn(move || receiver.run());
+        let snapshot = controller.shutdown().unwrap();
+        handle.join().unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn test_sink_backpressure_drop_newest() {
+        let snapshot = run_with_backpressure(BackpressurePolicy::DropNewest, |sink| {
+            sink.send(Sample::Count("ops".to_owned(), 1)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 10)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 100)).unwrap();
+        });
+
+        assert_eq!(snapshot.count(&"ops".to_owned()), Some(&1));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&2));
+    }
+
+    #[test]
+    fn test_sink_backpressure_drop_oldest() {
+        let snapshot = run_with_backpressure(BackpressurePolicy::DropOldest, |sink| {
+            sink.send(Sample::Count("ops".to_owned(), 1)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 10)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 100)).unwrap();
+        });
+
+        assert_eq!(snapshot.count(&"ops".to_owned()), Some(&100));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&2));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_batches"), Some(&2));
+    }
+
+    #[test]
+    fn test_sink_backpressure_grow() {
+        let snapshot = run_with_backpressure(BackpressurePolicy::Grow, |sink| {
+            sink.send(Sample::Count("ops".to_owned(), 1)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 10)).unwrap();
+            sink.send(Sample::Count("ops".to_owned(), 100)).unwrap();
+        });
+
+        assert_eq!(snapshot.count(&"ops".to_owned()), Some(&111));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&0));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_batches"), Some(&0));
+    }
+
+    #[test]
+    fn test_sink_try_send() {
+        let snapshot = run_with_backpressure(BackpressurePolicy::Block, |sink| {
+            sink.try_send(Sample::Count("ops".to_owned(), 1)).unwrap();
+            match sink.try_send(Sample::Count("ops".to_owned(), 10)) {
+                Err(Error::BufferPoolExhausted) => {},
+                res => panic!("unexpected result {:?}", res),
+            }
+        });
+
+        assert_eq!(snapshot.count(&"ops".to_owned()), Some(&1));
+        assert_eq!(snapshot.internal_count("hotmic_dropped_samples"), Some(&0));
+    }
+
+    #[test]
+    fn test_sink_try_send_channel_full() {
+        // More buffers than the data channel has room for, which only a growing pool allows.
+        let (data_tx, data_rx) = channel::channel(1);
+        let (control_tx, _control_rx) = channel::channel(1);
+        let (pool_tx, pool_rx) = crossbeam_channel::unbounded();
+        for _ in 0..3 {
+            pool_tx.send(Vec::with_capacity(2));
+        }
+        let stats = Arc::new(SinkStats::default());
+        let mut sink = Sink::new(pool_rx, data_tx, control_tx, 2, BackpressurePolicy::Block, stats, Arc::new(SystemClock));
+
+        sink.try_send(Sample::Count("ops".to_owned(), 1)).unwrap();
+        sink.try_send(Sample::Count("ops".to_owned(), 2)).unwrap();
+        sink.try_send(Sample::Count("ops".to_owned(), 3)).unwrap();
+        for _ in 0..3 {
+            match sink.try_send(Sample::Count("ops".to_owned(), 4)) {
+                Err(Error::ChannelFull) => {},
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+
+        // Only the samples that were accepted are sent, once there's room.
+        let sent = data_rx.recv().ok().unwrap();
+        assert_eq!(sent.len(), 2);
+        sink.flush().unwrap();
+        let sent = data_rx.recv().ok().unwrap();
+        assert_eq!(sent.len(), 1);
+        match sent[0].0 {
+            Sample::Count(_, 3) => {},
+            ref sample => panic!("unexpected sample {:?}", sample),
+        }
+    }
+
+    #[test]
+    fn test_sink_drop_full_channel() {
+        // The receiver is never run, so nothing ever makes room in the data channel.
+        let (data_tx, _data_rx) = channel::channel(1);
+        let (control_tx, _control_rx) = channel::channel(1);
+        let (pool_tx, pool_rx) = crossbeam_channel::unbounded();
+        for _ in 0..2 {
+            pool_tx.send(Vec::with_capacity(2));
+        }
+        let stats = Arc::new(SinkStats::default());
+        let clock = Arc::new(SystemClock);
+        let mut sink = Sink::new(pool_rx, data_tx, control_tx, 2, BackpressurePolicy::Block, stats.clone(), clock);
+
+        sink.send(Sample::Count("ops".to_owned(), 1)).unwrap();
+        sink.send(Sample::Count("ops".to_owned(), 2)).unwrap();
+        sink.send(Sample::Count("ops".to_owned(), 3)).unwrap();
+
+        // Even though the sink would block on a flush, dropping it doesn't.
+        drop(sink);
+        assert_eq!(stats.dropped_samples.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dropped_batches.load(Ordering::Relaxed), 1);
     }
 }